@@ -0,0 +1,160 @@
+use std::io::{self, Read};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use crate::error::TileError;
+
+// Size of brotli's internal buffer
+const BROTLI_BUFFER_SIZE: usize = 4096;
+// Real tiles are a few megabytes at most, anything inflating past this is broken or built to run us out of memory
+pub const MAX_DECOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// What a tile's bytes are compressed with, told apart by how they start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileEncoding {
+    Plain,
+    Gzip,
+    Zlib,
+    Brotli,
+}
+
+pub fn sniff_encoding(data: &[u8]) -> TileEncoding {
+    match data {
+        [] => TileEncoding::Plain,
+        // Vector tiles start with a layer, field 3 with wire type 2
+        [0x1a, ..] => TileEncoding::Plain,
+        [0x1f, 0x8b, ..] => TileEncoding::Gzip,
+        // A deflate method byte with a window zlib allows, and a header check that adds up
+        [cmf, flg, ..] if cmf & 0x0f == 8 && cmf >> 4 <= 7 && ((u16::from(*cmf) << 8) | u16::from(*flg)) % 31 == 0 => TileEncoding::Zlib,
+        // Brotli has no magic number, so anything else is given a try
+        _ => TileEncoding::Brotli,
+    }
+}
+
+/// Reads all of `decoder`, failing with `FileTooLarge` once it goes past `MAX_DECOMPRESSED_BYTES`
+pub fn read_decompressed(decoder: impl Read) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decoder.take(MAX_DECOMPRESSED_BYTES + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("more than {} MiB once decompressed", MAX_DECOMPRESSED_BYTES >> 20)));
+    }
+    Ok(out)
+}
+
+pub fn decompress_brotli(data: &[u8]) -> io::Result<Vec<u8>> {
+    read_decompressed(brotli::Decompressor::new(data, BROTLI_BUFFER_SIZE))
+}
+
+/// Decompresses gzip, zlib or brotli tile bytes, plain ones are returned as they are
+pub fn decompress_tile(data: Vec<u8>) -> Result<Vec<u8>, TileError> {
+    let encoding = sniff_encoding(&data);
+    let decompressed = match encoding {
+        TileEncoding::Plain => return Ok(data),
+        TileEncoding::Gzip => read_decompressed(GzDecoder::new(data.as_slice())),
+        TileEncoding::Zlib => read_decompressed(ZlibDecoder::new(data.as_slice())),
+        TileEncoding::Brotli => match decompress_brotli(&data) {
+            // Only brotli gets that far
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => Err(e),
+            // Not brotli after all, the tile decoder can say what is wrong with it
+            result => return Ok(result.unwrap_or(data)),
+        },
+    };
+    decompressed.map_err(|e| TileError::Decode(format!("{:?} decompression failed: {}", encoding, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::{GzEncoder, ZlibEncoder}, Compression};
+
+    use super::*;
+
+    /// A layer field with a name and a version, as plain vector tiles start
+    const TILE: &[u8] = b"\x1a\x09\x0a\x05water\x78\x02";
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut writer = brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, 5, 22);
+        writer.write_all(data).unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(TILE).unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(TILE).unwrap();
+
+        let cases = [
+            (gzip.finish().unwrap(), TileEncoding::Gzip),
+            (zlib.finish().unwrap(), TileEncoding::Zlib),
+            (brotli(TILE), TileEncoding::Brotli),
+        ];
+        for (compressed, encoding) in cases {
+            assert_eq!(sniff_encoding(&compressed), encoding);
+            assert_eq!(decompress_tile(compressed).unwrap(), TILE, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn plain_tiles_pass_through() {
+        assert_eq!(sniff_encoding(TILE), TileEncoding::Plain);
+        assert_eq!(decompress_tile(TILE.to_vec()).unwrap(), TILE);
+        assert_eq!(decompress_tile(Vec::new()).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn zlib_needs_a_valid_header() {
+        // The usual default, best compression and no compression headers
+        for header in [[0x78, 0x9c], [0x78, 0xda], [0x78, 0x01]] {
+            assert_eq!(sniff_encoding(&header), TileEncoding::Zlib, "{:02x?}", header);
+        }
+        // A deflate method byte with a check that doesn't add up, and a window bigger than zlib allows
+        for header in [[0x78, 0x00], [0x88, 0x98]] {
+            assert_eq!(sniff_encoding(&header), TileEncoding::Brotli, "{:02x?}", header);
+        }
+    }
+
+    #[test]
+    fn garbage() {
+        // Anything unrecognised is tried as brotli and handed on as it was when that fails
+        let garbage = b"\xff\xfe not a tile".to_vec();
+        assert_eq!(sniff_encoding(&garbage), TileEncoding::Brotli);
+        assert_eq!(decompress_tile(garbage.clone()).unwrap(), garbage);
+
+        // Magic numbers followed by nonsense are errors, naming what it was taken for
+        for (broken, codec) in [(b"\x1f\x8b not gzip".to_vec(), "Gzip"), (b"\x78\x9c not zlib".to_vec(), "Zlib")] {
+            match decompress_tile(broken) {
+                Err(TileError::Decode(message)) => assert!(message.starts_with(codec), "{}", message),
+                other => panic!("expected a {} decode error, got {:?}", codec, other),
+            }
+        }
+        // A gzip tile cut short
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(TILE).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert!(matches!(decompress_tile(gzip[..gzip.len() - 6].to_vec()), Err(TileError::Decode(_))));
+    }
+
+    #[test]
+    fn decompression_bombs_are_stopped() {
+        let zeros = vec![0; MAX_DECOMPRESSED_BYTES as usize + 1];
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&zeros).unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::best());
+        zlib.write_all(&zeros).unwrap();
+
+        for bomb in [gzip.finish().unwrap(), zlib.finish().unwrap(), brotli(&zeros)] {
+            assert!(bomb.len() < 1024 * 1024);
+            match decompress_tile(bomb) {
+                Err(TileError::Decode(message)) => assert!(message.contains("more than 64 MiB"), "{}", message),
+                other => panic!("expected a decode error, got {:?}", other.map(|data| data.len())),
+            }
+        }
+        // Right at the limit is still fine
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&zeros[1..]).unwrap();
+        assert_eq!(decompress_tile(gzip.finish().unwrap()).unwrap().len() as u64, MAX_DECOMPRESSED_BYTES);
+    }
+}
@@ -2,14 +2,16 @@ use bevy::{
     color::palettes::css::GOLD, diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin}, prelude::*
 };
 
+use crate::{tile_map::ZoomManager, worker_pool::TileWorkerPool};
+
 pub struct DebugPlugin;
 
 impl Plugin for DebugPlugin {
     fn build(&self, app: &mut App) {
         if cfg!(debug_assertions) {
             app.add_plugins(FrameTimeDiagnosticsPlugin)
-            .add_systems(Startup, (debug_draw_fps, debug_draw_entity_no))
-            .add_systems(Update, (text_update_fps, count_entities));
+            .add_systems(Startup, (debug_draw_fps, debug_draw_entity_no, debug_draw_tile_queue, debug_draw_zoom))
+            .add_systems(Update, (text_update_fps, count_entities, text_update_tile_queue, text_update_zoom));
         }
     }
 }
@@ -20,6 +22,12 @@ pub struct FpsText;
 #[derive(Component)]
 pub struct EntityText;
 
+#[derive(Component)]
+pub struct TileQueueText;
+
+#[derive(Component)]
+pub struct ZoomText;
+
 pub fn debug_draw_fps(mut commands: Commands, asset_server: Res<AssetServer>) {
     commands
     .spawn((
@@ -105,4 +113,84 @@ pub fn count_entities(query_entity: Query<Entity>, mut query: Query<&mut TextSpa
         let entity_count = query_entity.iter().count();
         **span = format!("{}", entity_count);
     }
-}
\ No newline at end of file
+}
+
+pub fn debug_draw_tile_queue(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+    .spawn((
+        Text::new("Tiles: "),
+        TextFont {
+            font: asset_server.load("fonts/BagnardSans.otf"),
+            font_size: 21.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(30.0),
+            right: Val::Px(5.0),
+            ..default()
+        },
+    ))
+    .with_child((
+        TextSpan::default(),
+            (
+                TextFont {
+                    font_size: 18.0,
+                    font: asset_server.load("fonts/BagnardSans.otf"),
+                    ..default()
+                },
+                TextColor(GOLD.into()),
+            ),
+        TileQueueText,
+    ));
+}
+
+/// Shows how many tiles are waiting for a worker, being worked on, and how many workers there are
+pub fn text_update_tile_queue(worker_pool: Option<Res<TileWorkerPool>>, mut query: Query<&mut TextSpan, With<TileQueueText>>) {
+    let Some(worker_pool) = worker_pool else {
+        return;
+    };
+    for mut span in &mut query {
+        **span = format!("{} queued, {}/{} working", worker_pool.queued(), worker_pool.in_progress(), worker_pool.workers());
+    }
+}
+
+pub fn debug_draw_zoom(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+    .spawn((
+        Text::new("Zoom: "),
+        TextFont {
+            font: asset_server.load("fonts/BagnardSans.otf"),
+            font_size: 21.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(30.0),
+            right: Val::Px(5.0),
+            ..default()
+        },
+    ))
+    .with_child((
+        TextSpan::default(),
+            (
+                TextFont {
+                    font_size: 18.0,
+                    font: asset_server.load("fonts/BagnardSans.otf"),
+                    ..default()
+                },
+                TextColor(GOLD.into()),
+            ),
+        ZoomText,
+    ));
+}
+
+/// Shows the zoom on screen next to the level tiles are loaded at
+pub fn text_update_zoom(zoom_manager: Option<Res<ZoomManager>>, mut query: Query<&mut TextSpan, With<ZoomText>>) {
+    let Some(zoom_manager) = zoom_manager else {
+        return;
+    };
+    for mut span in &mut query {
+        **span = format!("{:.2} (tiles at {})", zoom_manager.zoom, zoom_manager.zoom_level);
+    }
+}
@@ -0,0 +1,39 @@
+use std::{fmt, io};
+
+/// Everything that can go wrong between asking for a tile and having its image
+#[derive(Debug)]
+pub enum TileError {
+    /// The request never got a response, e.g. no connection or a timeout
+    Network(String),
+    /// The server answered with something other than 200
+    HttpStatus(u16),
+    /// The tile bytes aren't a valid vector tile
+    Decode(String),
+    /// Reading or writing a cache file or local tile archive failed
+    CacheIo(io::Error),
+    /// Rasterizing the tile failed
+    Render(String),
+    /// The tile was no longer wanted, so the work was stopped partway
+    Cancelled,
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::Network(e) => write!(f, "network error: {}", e),
+            TileError::HttpStatus(status) => write!(f, "server responded with HTTP {}", status),
+            TileError::Decode(e) => write!(f, "failed to decode tile: {}", e),
+            TileError::CacheIo(e) => write!(f, "cache I/O error: {}", e),
+            TileError::Render(e) => write!(f, "failed to render tile: {}", e),
+            TileError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for TileError {}
+
+impl From<io::Error> for TileError {
+    fn from(e: io::Error) -> Self {
+        TileError::CacheIo(e)
+    }
+}
@@ -0,0 +1,341 @@
+use std::{collections::hash_map::RandomState, hash::{BuildHasher, Hasher}, io::Read, sync::{Arc, Condvar, Mutex, OnceLock}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::error::TileError;
+
+/// Tile providers ask to be told who is fetching their tiles
+pub const USER_AGENT: &str = concat!("bevy-ofm-viewer/", env!("CARGO_PKG_VERSION"));
+
+/// How the HTTP client behaves, the defaults are meant for public tile servers
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    pub connect_timeout: Duration,
+    /// For the whole request, body included
+    pub timeout: Duration,
+    /// How many times a failed request is tried again before giving up
+    pub max_retries: u32,
+    /// The first retry waits about this long, doubling every time after
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// A `Retry-After` longer than this is not waited out, the request fails instead
+    pub max_retry_after: Duration,
+    /// How many requests may be in flight at once, across every thread using the client
+    pub max_connections: usize,
+    pub user_agent: String,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            max_retries: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(60),
+            max_connections: 6,
+            user_agent: USER_AGENT.to_string(),
+        }
+    }
+}
+
+/// A response with its body read in full
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+    headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    fn read(response: ureq::Response) -> Result<Self, TileError> {
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = response.header(&name)?.to_string();
+                Some((name, value))
+            })
+            .collect();
+        let status = response.status();
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body).map_err(|e| TileError::Network(e.to_string()))?;
+        Ok(Self { status, body, headers })
+    }
+
+    /// Header names are matched ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Caps how many requests are in flight, threads wait for a slot
+struct ConnectionLimit {
+    active: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+struct ConnectionPermit<'a>(&'a ConnectionLimit);
+
+impl ConnectionLimit {
+    fn acquire(&self) -> ConnectionPermit<'_> {
+        let mut active = self.active.lock().unwrap();
+        while *active >= self.max {
+            active = self.freed.wait(active).unwrap();
+        }
+        *active += 1;
+        ConnectionPermit(self)
+    }
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+enum Attempt {
+    Done(Result<HttpResponse, TileError>),
+    Retry { error: TileError, after: Option<Duration> },
+}
+
+/// Fetches over HTTP with timeouts, retries and a limit on connections, cheap to clone
+#[derive(Clone)]
+pub struct HttpClient {
+    agent: ureq::Agent,
+    settings: Arc<HttpSettings>,
+    limit: Arc<ConnectionLimit>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(HttpSettings::default())
+    }
+}
+
+impl HttpClient {
+    pub fn new(settings: HttpSettings) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(settings.connect_timeout)
+            .timeout(settings.timeout)
+            .user_agent(&settings.user_agent)
+            .build();
+        Self {
+            agent,
+            limit: Arc::new(ConnectionLimit {
+                active: Mutex::new(0),
+                freed: Condvar::new(),
+                max: settings.max_connections.max(1),
+            }),
+            settings: Arc::new(settings),
+        }
+    }
+
+    /// The client everything shares by default, so the connection limit holds across the whole app
+    pub fn global() -> &'static HttpClient {
+        static GLOBAL: OnceLock<HttpClient> = OnceLock::new();
+        GLOBAL.get_or_init(HttpClient::default)
+    }
+
+    /// GETs `url`, trying again on timeouts, dropped connections, 429 and 5xx responses.
+    /// Other statuses below 400 are returned as they are, the rest are an error.
+    pub fn get(&self, url: &str, headers: &[(&str, &str)]) -> Result<HttpResponse, TileError> {
+        let mut retries = 0;
+        loop {
+            let (error, after) = match self.attempt(url, headers) {
+                Attempt::Done(result) => return result,
+                Attempt::Retry { error, after } => (error, after),
+            };
+            if retries >= self.settings.max_retries {
+                return Err(error);
+            }
+
+            let delay = match after {
+                Some(after) if after > self.settings.max_retry_after => return Err(error),
+                Some(after) => after,
+                None => self.backoff(retries),
+            };
+            retries += 1;
+            thread::sleep(delay);
+        }
+    }
+
+    fn attempt(&self, url: &str, headers: &[(&str, &str)]) -> Attempt {
+        let _permit = self.limit.acquire();
+        let mut request = self.agent.get(url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        match request.call() {
+            Ok(response) => match HttpResponse::read(response) {
+                Ok(response) => Attempt::Done(Ok(response)),
+                // The connection dropped halfway through the body
+                Err(error) => Attempt::Retry { error, after: None },
+            },
+            Err(ureq::Error::Status(status, response)) if status == 429 || status >= 500 => Attempt::Retry {
+                error: TileError::HttpStatus(status),
+                after: response.header("Retry-After").and_then(|after| parse_retry_after(after, SystemTime::now())),
+            },
+            Err(ureq::Error::Status(status, _)) => Attempt::Done(Err(TileError::HttpStatus(status))),
+            Err(e) => Attempt::Retry {
+                error: TileError::Network(e.to_string()),
+                after: None,
+            },
+        }
+    }
+
+    /// Exponential backoff with jitter, somewhere between half and all of the doubled delay,
+    /// so workers that failed together don't all come back at once
+    fn backoff(&self, retries: u32) -> Duration {
+        let delay = self.settings.base_delay.saturating_mul(1 << retries.min(16)).min(self.settings.max_delay);
+        let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date, like `Wed, 21 Oct 2015 07:28:00 GMT`.
+/// A date already past means right away.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = UNIX_EPOCH + Duration::from_secs(parse_http_date(value)?);
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Seconds since the Unix epoch of an IMF-fixdate, the only form servers may send
+fn parse_http_date(value: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let [_weekday, day, month, year, time, "GMT"] = value.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let [hours, minutes, seconds] = time.split(':').map(|part| part.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?[..] else {
+        return None;
+    };
+    if year < 1970 || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    // Days from the epoch to the civil date, counting years from March so the leap day comes last
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    Some(days * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{io::Write, net::TcpListener, time::Instant};
+
+    use super::*;
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ntile";
+    const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    /// Answers one connection with each response in turn, and hands back the requests it got
+    pub(crate) fn serve(responses: Vec<impl AsRef<[u8]> + Send + 'static>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/0/0/0.pbf", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                received.lock().unwrap().push(String::from_utf8_lossy(&request).to_string());
+                stream.write_all(response.as_ref()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// A client that retries without waiting long
+    pub(crate) fn client(max_retries: u32) -> HttpClient {
+        HttpClient::new(HttpSettings {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            ..HttpSettings::default()
+        })
+    }
+
+    #[test]
+    fn waits_out_retry_after() {
+        let (url, requests) = serve(vec!["HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", OK]);
+        let start = Instant::now();
+        let response = client(4).get(&url, &[]).unwrap();
+        assert_eq!(response.body, b"tile");
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (url, requests) = serve(vec![UNAVAILABLE, OK]);
+        let response = client(4).get(&url, &[]).unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (200, b"tile".as_slice()));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let (url, requests) = serve(vec![UNAVAILABLE; 3]);
+        assert!(matches!(client(2).get(&url, &[]), Err(TileError::HttpStatus(503))));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn fails_on_long_retry_after() {
+        let (url, requests) = serve(vec!["HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+        let start = Instant::now();
+        assert!(matches!(client(4).get(&url, &[]), Err(TileError::HttpStatus(503))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retry_after_dates() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(parse_http_date(date), Some(784_111_777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"), Some(1_709_251_199));
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777 - 30);
+        assert_eq!(parse_retry_after(date, now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after(date, now + Duration::from_secs(60)), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after(" 12 ", now), Some(Duration::from_secs(12)));
+        // The obsolete forms servers mustn't send anymore
+        assert_eq!(parse_retry_after("Sunday, 06-Nov-94 08:49:37 GMT", now), None);
+        assert_eq!(parse_retry_after("Sun Nov  6 08:49:37 1994", now), None);
+    }
+
+    #[test]
+    fn fails_on_far_retry_after_date() {
+        let (url, requests) = serve(vec!["HTTP/1.1 503 Service Unavailable\r\nRetry-After: Fri, 31 Dec 2100 23:59:59 GMT\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+        assert!(matches!(client(4).get(&url, &[]), Err(TileError::HttpStatus(503))));
+        assert_eq!(requests.lock().unwrap().len(), 1, "the date should be read, not retried on the usual backoff");
+    }
+
+    #[test]
+    fn sends_user_agent() {
+        let (url, requests) = serve(vec![OK]);
+        client(0).get(&url, &[("If-None-Match", "\"abc\"")]).unwrap();
+        let request = requests.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(request.contains(&format!("user-agent: {}\r\n", USER_AGENT.to_ascii_lowercase())), "{}", request);
+        assert!(request.contains("if-none-match: \"abc\"\r\n"), "{}", request);
+    }
+}
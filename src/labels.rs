@@ -0,0 +1,605 @@
+use std::{collections::{HashMap, HashSet}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use bevy::prelude::*;
+use font_kit::{font::Font, hinting::HintingOptions, outline::OutlineSink};
+use pathfinder_geometry::{line_segment::LineSegment2F, vector::Vector2F};
+use raqote::{AntialiasMode, DrawOptions, DrawTarget, LineJoin, PathBuilder, Point, Source, StrokeStyle, Transform};
+use rstar::{RTree, RTreeObject, AABB};
+
+use crate::style::{Properties, PropertyValue, StyleColor};
+
+const FONT_BYTES: &[u8] = include_bytes!("../assets/fonts/BagnardSans.otf");
+/// Extra room kept free around every label, in pixels
+const LABEL_PADDING: f64 = 4.0;
+/// How close two labels with the same text may be, so road names aren't repeated in every tile
+const REPEAT_DISTANCE: f64 = 256.0;
+/// Line labels are dropped where the line bends more than this between two glyphs
+const MAX_GLYPH_ANGLE: f32 = std::f32::consts::FRAC_PI_4;
+
+thread_local! {
+    // font-kit fonts can't be shared between threads, so every tile worker loads its own copy
+    static FONT: Option<Font> = Font::from_bytes(Arc::new(FONT_BYTES.to_vec()), 0).ok();
+}
+
+#[derive(Debug, Clone)]
+struct PlacedLabel {
+    text: String,
+    owner: (u64, u64),
+    bounds: AABB<[f64; 2]>,
+    /// How to draw it, for neighbours the label reaches into
+    drawing: Arc<LabelDrawing>,
+}
+
+/// The glyphs of a placed label, positioned in pixels of the tile that owns it
+#[derive(Debug)]
+struct LabelDrawing {
+    glyphs: Vec<u32>,
+    placement: Vec<(Point, f32)>,
+    font_size: f32,
+    color: StyleColor,
+    halo: Option<StyleColor>,
+}
+
+impl PlacedLabel {
+    fn same_as(&self, other: &PlacedLabel) -> bool {
+        self.owner == other.owner && self.text == other.text && self.bounds == other.bounds
+    }
+}
+
+impl RTreeObject for PlacedLabel {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        self.bounds
+    }
+}
+
+/// Every label placed so far, per zoom level, in pixels from the top left of the world.
+/// Shared by all tile workers so labels from neighbouring tiles don't overlap.
+/// A label belongs to the tile its middle is in, tiles it reaches into draw the part that overflows.
+#[derive(Default)]
+pub struct LabelIndex {
+    levels: Mutex<HashMap<u32, RTree<PlacedLabel>>>,
+    /// Zoom, x and y of the tiles drawn with the labels around them at the time, and the drawing that started last
+    drawn: Mutex<HashMap<(u32, u64, u64), u64>>,
+    /// Drawn tiles a label placed since reaches into
+    redraw: Mutex<HashSet<(u32, u64, u64)>>,
+    drawings: AtomicU64,
+}
+
+impl LabelIndex {
+    /// A number no other drawing of a tile has, to start it with
+    pub fn new_drawing(&self) -> u64 {
+        self.drawings.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Marks a tile as drawn by `drawing`. Its labels are kept, drawing it again places the same ones.
+    pub fn start_tile(&self, zoom: u32, x: u64, y: u64, drawing: u64) {
+        // Labels placed before this are picked up by this drawing
+        self.redraw.lock().unwrap().remove(&(zoom, x, y));
+        self.drawn.lock().unwrap().insert((zoom, x, y), drawing);
+    }
+
+    /// Drawn tiles a neighbour's label has reached into since, they have to be drawn again to show it
+    pub fn take_redraws(&self) -> Vec<(u32, u64, u64)> {
+        self.redraw.lock().unwrap().drain().collect()
+    }
+
+    /// Forgets the labels of a tile whose image is gone, so they don't keep other labels out
+    pub fn forget_tile(&self, zoom: u32, x: u64, y: u64) {
+        self.forget(zoom, x, y, None);
+    }
+
+    /// Forgets the labels of a drawing that is thrown away, unless the tile has been drawn again since
+    /// and they are the newer drawing's now
+    pub fn forget_drawing(&self, zoom: u32, x: u64, y: u64, drawing: u64) {
+        self.forget(zoom, x, y, Some(drawing));
+    }
+
+    fn forget(&self, zoom: u32, x: u64, y: u64, drawing: Option<u64>) {
+        // Held throughout, so a drawing starting meanwhile finds its labels gone and places them again
+        let mut levels = self.levels.lock().unwrap();
+        {
+            let mut drawn = self.drawn.lock().unwrap();
+            let last = drawn.get(&(zoom, x, y)).copied();
+            if drawing.is_some_and(|drawing| last.is_some_and(|last| last != drawing)) {
+                return;
+            }
+            drawn.remove(&(zoom, x, y));
+        }
+        self.redraw.lock().unwrap().remove(&(zoom, x, y));
+        let Some(tree) = levels.get_mut(&zoom) else {
+            return;
+        };
+        let kept: Vec<PlacedLabel> = tree.iter().filter(|label| label.owner != (x, y)).cloned().collect();
+        if kept.is_empty() {
+            levels.remove(&zoom);
+        } else if kept.len() < tree.size() {
+            *tree = RTree::bulk_load(kept);
+        }
+    }
+
+    /// Claims the area for a label, or returns false if something is already there.
+    /// A tile drawn again finds its own labels where it left them.
+    fn try_place(&self, zoom: u32, tile_size: f64, label: PlacedLabel) -> bool {
+        let mut levels = self.levels.lock().unwrap();
+        let tree = levels.entry(zoom).or_default();
+
+        let (min, max) = (label.bounds.lower(), label.bounds.upper());
+        let world = tile_size * (1_u64 << zoom) as f64;
+        let padded = AABB::from_corners([min[0] - LABEL_PADDING, min[1] - LABEL_PADDING], [max[0] + LABEL_PADDING, max[1] + LABEL_PADDING]);
+        let overlapping: Vec<&PlacedLabel> = wrapped(&padded, world).iter().flat_map(|area| tree.locate_in_envelope_intersecting(area)).collect();
+        if !overlapping.is_empty() {
+            return overlapping.iter().any(|other| other.same_as(&label));
+        }
+        let nearby = AABB::from_corners([min[0] - REPEAT_DISTANCE, min[1] - REPEAT_DISTANCE], [max[0] + REPEAT_DISTANCE, max[1] + REPEAT_DISTANCE]);
+        if wrapped(&nearby, world).iter().any(|area| tree.locate_in_envelope_intersecting(area).any(|other| other.text == label.text)) {
+            return false;
+        }
+
+        // Neighbours drawn without it have to be drawn again, past the antimeridian that's the other end of the row
+        let drawn = self.drawn.lock().unwrap();
+        let mut redraw = self.redraw.lock().unwrap();
+        for x in (min[0] / tile_size).floor() as i64..=(max[0] / tile_size).floor() as i64 {
+            let x = x.rem_euclid(1 << zoom) as u64;
+            for y in (min[1] / tile_size).floor().max(0.0) as u64..=(max[1] / tile_size).floor().max(0.0) as u64 {
+                if (x, y) != label.owner && drawn.contains_key(&(zoom, x, y)) {
+                    redraw.insert((zoom, x, y));
+                }
+            }
+        }
+
+        tree.insert(label);
+        true
+    }
+
+    /// Labels of other tiles reaching into this one, with how many tiles east of it their owner is
+    fn overflowing_into(&self, zoom: u32, x: u64, y: u64, tile_size: f64) -> Vec<(PlacedLabel, i64)> {
+        let levels = self.levels.lock().unwrap();
+        let Some(tree) = levels.get(&zoom) else {
+            return Vec::new();
+        };
+        let tiles = 1_i64 << zoom;
+        let tile = AABB::from_corners([x as f64 * tile_size, y as f64 * tile_size], [(x + 1) as f64 * tile_size, (y + 1) as f64 * tile_size]);
+        // The copies one world east and west find the labels reaching across the antimeridian
+        wrapped(&tile, tiles as f64 * tile_size)
+            .iter()
+            .zip([-tiles, 0, tiles])
+            .flat_map(|(area, shift)| {
+                tree.locate_in_envelope_intersecting(area)
+                    .filter(|label| label.owner != (x, y))
+                    .map(move |label| (label.clone(), label.owner.0 as i64 - (x as i64 + shift)))
+            })
+            .collect()
+    }
+}
+
+/// An area and its copies one world to the west and east
+fn wrapped(area: &AABB<[f64; 2]>, world: f64) -> [AABB<[f64; 2]>; 3] {
+    let (min, max) = (area.lower(), area.upper());
+    [-world, 0.0, world].map(|shift| AABB::from_corners([min[0] + shift, min[1]], [max[0] + shift, max[1]]))
+}
+
+/// How a symbol layer's labels look
+#[derive(Debug, Clone, Copy)]
+pub struct LabelStyle<'a> {
+    /// Properties to take the text from, the first one a feature has is used
+    pub text_fields: &'a [String],
+    pub font_size: f32,
+    pub color: StyleColor,
+    pub halo: Option<StyleColor>,
+}
+
+/// Places and draws the labels of a single tile. Works in pixels of the rendered tile.
+pub struct LabelPlacer<'a> {
+    pub index: &'a LabelIndex,
+    pub zoom: u32,
+    pub x: u64,
+    pub y: u64,
+    pub size: f32,
+    /// Tile units to pixels
+    pub scale: f32,
+    /// Where the tile starts in the scaled data, only not zero for overzoomed tiles
+    pub origin: Point,
+}
+
+struct Glyph {
+    id: u32,
+    advance: f32,
+}
+
+impl LabelPlacer<'_> {
+    pub fn place(&self, dt: &mut DrawTarget, geometry: &geo::Geometry<f32>, properties: &Properties, style: &LabelStyle) {
+        let LabelStyle { text_fields, font_size, color, halo } = *style;
+        let Some(text) = text_fields.iter().find_map(|field| properties.get(field).and_then(PropertyValue::to_label_text)) else {
+            return;
+        };
+
+        FONT.with(|font| {
+            let Some(font) = font else {
+                return;
+            };
+            let glyphs = layout(font, &text, font_size);
+            let placement = match geometry {
+                geo::Geometry::Point(point) => self.place_point(point, &glyphs, font_size),
+                geo::Geometry::MultiPoint(multi_point) => multi_point.iter().next().and_then(|point| self.place_point(point, &glyphs, font_size)),
+                geo::Geometry::LineString(line_string) => self.place_along_line(line_string, &glyphs, font_size),
+                geo::Geometry::MultiLineString(multi_line_string) => multi_line_string
+                    .iter()
+                    .max_by(|a, b| line_length(a).total_cmp(&line_length(b)))
+                    .and_then(|line_string| self.place_along_line(line_string, &glyphs, font_size)),
+                _ => None,
+            };
+            let Some(placement) = placement else {
+                return;
+            };
+
+            // Neighbours get the feature too as part of their buffer, the tile the label's middle is in places it
+            let (min, max) = placement_bounds(&placement, font_size);
+            let middle = Point::new((min.x + max.x) / 2.0, (min.y + max.y) / 2.0);
+            if middle.x < 0.0 || middle.y < 0.0 || middle.x >= self.size || middle.y >= self.size {
+                return;
+            }
+            let origin = [self.x as f64 * self.size as f64, self.y as f64 * self.size as f64];
+            let drawing = Arc::new(LabelDrawing {
+                glyphs: glyphs.iter().map(|glyph| glyph.id).collect(),
+                placement,
+                font_size,
+                color,
+                halo,
+            });
+            let label = PlacedLabel {
+                text,
+                owner: (self.x, self.y),
+                bounds: AABB::from_corners(
+                    [origin[0] + min.x as f64, origin[1] + min.y as f64],
+                    [origin[0] + max.x as f64, origin[1] + max.y as f64],
+                ),
+                drawing: drawing.clone(),
+            };
+            if !self.index.try_place(self.zoom, self.size as f64, label) {
+                return;
+            }
+
+            draw_label(dt, font, &drawing, Point::new(0.0, 0.0));
+        });
+    }
+
+    /// Draws the parts of neighbouring tiles' labels that reach into this tile, call once the tile's own are placed
+    pub fn draw_overflow(&self, dt: &mut DrawTarget) {
+        let overflowing = self.index.overflowing_into(self.zoom, self.x, self.y, self.size as f64);
+        if overflowing.is_empty() {
+            return;
+        }
+        FONT.with(|font| {
+            let Some(font) = font else {
+                return;
+            };
+            for (label, east) in overflowing {
+                // Owners are next door, so the offset is small enough for f32
+                let offset = Point::new(east as f32 * self.size, (label.owner.1 as f64 - self.y as f64) as f32 * self.size);
+                draw_label(dt, font, &label.drawing, offset);
+            }
+        });
+    }
+
+    fn place_point(&self, point: &geo::Point<f32>, glyphs: &[Glyph], font_size: f32) -> Option<Vec<(Point, f32)>> {
+        let anchor = Point::new(point.x() * self.scale - self.origin.x, point.y() * self.scale - self.origin.y);
+        let width: f32 = glyphs.iter().map(|glyph| glyph.advance).sum();
+        let mut x = anchor.x - width / 2.0;
+        let baseline = anchor.y + font_size * 0.35;
+        Some(glyphs.iter().map(|glyph| {
+            let position = (Point::new(x, baseline), 0.0);
+            x += glyph.advance;
+            position
+        }).collect())
+    }
+
+    fn place_along_line(&self, line_string: &geo::LineString<f32>, glyphs: &[Glyph], font_size: f32) -> Option<Vec<(Point, f32)>> {
+        let mut points: Vec<Point> = line_string.0.iter().map(|point| Point::new(point.x * self.scale - self.origin.x, point.y * self.scale - self.origin.y)).collect();
+        let (first, last) = (*points.first()?, *points.last()?);
+        // Keep the text reading left to right
+        if last.x < first.x {
+            points.reverse();
+        }
+
+        let width: f32 = glyphs.iter().map(|glyph| glyph.advance).sum();
+        let length: f32 = points.windows(2).map(|pair| (pair[1] - pair[0]).length()).sum();
+        if length < width + font_size {
+            return None;
+        }
+
+        let mut distance = (length - width) / 2.0;
+        let mut previous_angle = None;
+        let mut placement = Vec::with_capacity(glyphs.len());
+        for glyph in glyphs {
+            let (center, angle) = point_along(&points, distance + glyph.advance / 2.0)?;
+            if previous_angle.is_some_and(|previous: f32| angle_between(previous, angle) > MAX_GLYPH_ANGLE) {
+                return None;
+            }
+            previous_angle = Some(angle);
+
+            let (sin, cos) = angle.sin_cos();
+            // Glyphs are drawn from their left edge on the baseline, so step back half an advance and drop below the line
+            let origin = Point::new(
+                center.x - cos * glyph.advance / 2.0 - sin * font_size * 0.35,
+                center.y - sin * glyph.advance / 2.0 + cos * font_size * 0.35,
+            );
+            placement.push((origin, angle));
+            distance += glyph.advance;
+        }
+        Some(placement)
+    }
+}
+
+fn layout(font: &Font, text: &str, font_size: f32) -> Vec<Glyph> {
+    let units_per_em = font.metrics().units_per_em as f32;
+    text.chars()
+        .filter_map(|c| font.glyph_for_char(c))
+        .map(|id| Glyph {
+            id,
+            advance: font.advance(id).map(|advance| advance.x()).unwrap_or(0.0) * font_size / units_per_em,
+        })
+        .collect()
+}
+
+fn line_length(line_string: &geo::LineString<f32>) -> f32 {
+    line_string.lines().map(|line| (line.dx() * line.dx() + line.dy() * line.dy()).sqrt()).sum()
+}
+
+/// The point at `distance` along the line and the direction of the line there
+fn point_along(points: &[Point], mut distance: f32) -> Option<(Point, f32)> {
+    for pair in points.windows(2) {
+        let segment = pair[1] - pair[0];
+        let length = segment.length();
+        if distance <= length && length > 0.0 {
+            return Some((pair[0] + segment * (distance / length), segment.y.atan2(segment.x)));
+        }
+        distance -= length;
+    }
+    None
+}
+
+fn angle_between(a: f32, b: f32) -> f32 {
+    let difference = (b - a).rem_euclid(std::f32::consts::TAU);
+    difference.min(std::f32::consts::TAU - difference)
+}
+
+fn placement_bounds(placement: &[(Point, f32)], font_size: f32) -> (Point, Point) {
+    let mut min = Point::new(f32::MAX, f32::MAX);
+    let mut max = Point::new(f32::MIN, f32::MIN);
+    for (origin, _) in placement {
+        min = Point::new(min.x.min(origin.x - font_size), min.y.min(origin.y - font_size));
+        max = Point::new(max.x.max(origin.x + font_size), max.y.max(origin.y + font_size / 2.0));
+    }
+    (min, max)
+}
+
+/// A glyph outline in pixels, with y pointing down
+struct GlyphPath {
+    builder: PathBuilder,
+    /// Font units to pixels
+    scale: f32,
+}
+
+impl GlyphPath {
+    fn point(&self, point: Vector2F) -> (f32, f32) {
+        (point.x() * self.scale, -point.y() * self.scale)
+    }
+}
+
+impl OutlineSink for GlyphPath {
+    fn move_to(&mut self, to: Vector2F) {
+        let (x, y) = self.point(to);
+        self.builder.move_to(x, y);
+    }
+
+    fn line_to(&mut self, to: Vector2F) {
+        let (x, y) = self.point(to);
+        self.builder.line_to(x, y);
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: Vector2F, to: Vector2F) {
+        let ((cx, cy), (x, y)) = (self.point(ctrl), self.point(to));
+        self.builder.quad_to(cx, cy, x, y);
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: LineSegment2F, to: Vector2F) {
+        let ((c1x, c1y), (c2x, c2y), (x, y)) = (self.point(ctrl.from()), self.point(ctrl.to()), self.point(to));
+        self.builder.cubic_to(c1x, c1y, c2x, c2y, x, y);
+    }
+
+    fn close(&mut self) {
+        self.builder.close();
+    }
+}
+
+/// Draws a label moved by `offset`, the pixels from this tile to the one it was placed in
+fn draw_label(dt: &mut DrawTarget, font: &Font, label: &LabelDrawing, offset: Point) {
+    let previous_transform = *dt.get_transform();
+    let options = DrawOptions {
+        antialias: AntialiasMode::Gray,
+        ..Default::default()
+    };
+    let halo_style = StrokeStyle {
+        width: 2.0,
+        join: LineJoin::Round,
+        ..Default::default()
+    };
+    let scale = label.font_size / font.metrics().units_per_em as f32;
+
+    // Filled as paths, raqote's draw_glyphs leaves out glyphs that are moved or turned by the transform
+    for (glyph, (origin, angle)) in label.glyphs.iter().zip(&label.placement) {
+        let mut path = GlyphPath {
+            builder: PathBuilder::new(),
+            scale,
+        };
+        if font.outline(*glyph, HintingOptions::None, &mut path).is_err() {
+            continue;
+        }
+        let path = path.builder.finish();
+
+        let (sin, cos) = angle.sin_cos();
+        dt.set_transform(&Transform::new(cos, sin, -sin, cos, origin.x + offset.x, origin.y + offset.y));
+        if let Some(halo) = label.halo {
+            dt.stroke(&path, &Source::Solid(halo.to_source()), &halo_style, &options);
+        }
+        dt.fill(&path, &Source::Solid(label.color.to_source()), &options);
+    }
+
+    dt.set_transform(&previous_transform);
+}
+
+/// The label index shared by every tile worker
+#[derive(Resource, Clone, Default, Deref)]
+pub struct Labels(pub Arc<LabelIndex>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILE_SIZE: f64 = 256.0;
+
+    /// A label covering `min` to `max` in world pixels, with nothing to draw
+    fn label(text: &str, owner: (u64, u64), min: [f64; 2], max: [f64; 2]) -> PlacedLabel {
+        PlacedLabel {
+            text: text.to_string(),
+            owner,
+            bounds: AABB::from_corners(min, max),
+            drawing: Arc::new(LabelDrawing {
+                glyphs: Vec::new(),
+                placement: Vec::new(),
+                font_size: 12.0,
+                color: StyleColor { r: 0, g: 0, b: 0, a: 0xff },
+                halo: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn labels_keep_clear_of_each_other() {
+        let index = LabelIndex::default();
+        assert!(index.try_place(14, TILE_SIZE, label("Mill Road", (0, 0), [10.0, 10.0], [60.0, 30.0])));
+        // Overlapping, and within the padding
+        assert!(!index.try_place(14, TILE_SIZE, label("Hills Road", (0, 0), [50.0, 20.0], [100.0, 40.0])));
+        assert!(!index.try_place(14, TILE_SIZE, label("Hills Road", (0, 0), [62.0, 10.0], [100.0, 30.0])));
+        // The same text is kept further apart, so it isn't repeated in every tile
+        assert!(!index.try_place(14, TILE_SIZE, label("Mill Road", (1, 0), [300.0, 10.0], [350.0, 30.0])));
+        assert!(index.try_place(14, TILE_SIZE, label("Mill Road", (1, 0), [400.0, 10.0], [450.0, 30.0])));
+        assert!(index.try_place(14, TILE_SIZE, label("Hills Road", (0, 0), [100.0, 100.0], [150.0, 120.0])));
+        // A tile drawn again finds its labels where it left them
+        assert!(index.try_place(14, TILE_SIZE, label("Mill Road", (0, 0), [10.0, 10.0], [60.0, 30.0])));
+        // Other levels are separate
+        assert!(index.try_place(13, TILE_SIZE, label("Hills Road", (0, 0), [50.0, 20.0], [100.0, 40.0])));
+
+        index.forget_tile(14, 0, 0);
+        assert!(index.try_place(14, TILE_SIZE, label("Hills Road", (0, 0), [50.0, 20.0], [100.0, 40.0])));
+    }
+
+    #[test]
+    fn thrown_away_drawings_keep_a_newer_drawings_labels() {
+        let index = LabelIndex::default();
+        let (old, new) = (index.new_drawing(), index.new_drawing());
+        index.start_tile(14, 0, 0, old);
+        assert!(index.try_place(14, TILE_SIZE, label("Mill Road", (0, 0), [10.0, 10.0], [60.0, 30.0])));
+        // Drawn again, finding the label, before the old drawing's result is thrown away
+        index.start_tile(14, 0, 0, new);
+        assert!(index.try_place(14, TILE_SIZE, label("Mill Road", (0, 0), [10.0, 10.0], [60.0, 30.0])));
+        index.forget_drawing(14, 0, 0, old);
+        assert!(!index.try_place(14, TILE_SIZE, label("Hills Road", (0, 0), [50.0, 20.0], [100.0, 40.0])), "the label should still be there");
+
+        index.forget_drawing(14, 0, 0, new);
+        assert!(index.try_place(14, TILE_SIZE, label("Hills Road", (0, 0), [50.0, 20.0], [100.0, 40.0])));
+    }
+
+    #[test]
+    fn drawn_neighbours_are_redrawn() {
+        let index = LabelIndex::default();
+        for x in 0..3 {
+            index.start_tile(14, x, 5, 0);
+        }
+        // Inside its own tile, then reaching into the drawn tile east of it and the undrawn one south
+        index.try_place(14, TILE_SIZE, label("Mill Road", (0, 5), [10.0, 1290.0], [60.0, 1300.0]));
+        assert!(index.take_redraws().is_empty());
+        index.try_place(14, TILE_SIZE, label("Hills Road", (0, 5), [200.0, 1520.0], [300.0, 1540.0]));
+        assert_eq!(index.take_redraws(), [(14, 1, 5)]);
+        assert!(index.take_redraws().is_empty(), "taking them clears them");
+
+        // Drawing the tile again picks up the label, so it needs no redraw
+        index.try_place(14, TILE_SIZE, label("Station Road", (2, 5), [500.0, 1290.0], [560.0, 1300.0]));
+        index.start_tile(14, 1, 5, 0);
+        assert!(index.take_redraws().is_empty());
+    }
+
+    #[test]
+    fn labels_overflow_into_their_neighbours() {
+        let index = LabelIndex::default();
+        index.try_place(14, TILE_SIZE, label("Mill Road", (0, 5), [200.0, 1290.0], [300.0, 1300.0]));
+        let overflowing = index.overflowing_into(14, 1, 5, TILE_SIZE);
+        let owners: Vec<_> = overflowing.iter().map(|(label, east)| (label.text.as_str(), label.owner, *east)).collect();
+        assert_eq!(owners, [("Mill Road", (0, 5), -1)]);
+        assert!(index.overflowing_into(14, 0, 5, TILE_SIZE).is_empty(), "a tile's own labels are drawn when placed");
+        assert!(index.overflowing_into(14, 2, 5, TILE_SIZE).is_empty());
+    }
+
+    #[test]
+    fn labels_reach_across_the_antimeridian() {
+        // Four tiles across at zoom 2, so the row ends at 1024
+        let index = LabelIndex::default();
+        index.start_tile(2, 0, 1, 0);
+        index.start_tile(2, 3, 1, 0);
+
+        index.try_place(2, TILE_SIZE, label("Fiji", (0, 1), [-30.0, 300.0], [40.0, 320.0]));
+        assert_eq!(index.take_redraws(), [(2, 3, 1)]);
+        let overflowing: Vec<_> = index.overflowing_into(2, 3, 1, TILE_SIZE).into_iter().map(|(label, east)| (label.owner, east)).collect();
+        assert_eq!(overflowing, [((0, 1), 1)]);
+
+        index.try_place(2, TILE_SIZE, label("Chukotka", (3, 1), [1000.0, 400.0], [1060.0, 420.0]));
+        assert_eq!(index.take_redraws(), [(2, 0, 1)]);
+        let overflowing: Vec<_> = index.overflowing_into(2, 0, 1, TILE_SIZE).into_iter().map(|(label, east)| (label.owner, east)).collect();
+        assert_eq!(overflowing, [((3, 1), -1)]);
+
+        // Labels on either side of it collide too
+        assert!(!index.try_place(2, TILE_SIZE, label("Samoa", (3, 1), [980.0, 300.0], [1020.0, 320.0])));
+    }
+
+    #[test]
+    fn overflow_is_drawn_in_the_neighbour() {
+        let index = LabelIndex::default();
+        let size = 64;
+        let placer = |x: u64| LabelPlacer {
+            index: &index,
+            zoom: 14,
+            x,
+            y: 0,
+            size: size as f32,
+            scale: size as f32 / 4096.0,
+            origin: Point::new(0.0, 0.0),
+        };
+        let text_fields = ["name".to_string()];
+        let style = LabelStyle {
+            text_fields: &text_fields,
+            font_size: 16.0,
+            color: StyleColor { r: 0, g: 0, b: 0, a: 0xff },
+            halo: None,
+        };
+        let properties = Properties::from([("name".to_string(), PropertyValue::String("Cambridge".to_string()))]);
+        let drawn = |dt: &DrawTarget, columns: std::ops::Range<usize>| {
+            dt.get_data().chunks(size).any(|row| row[columns.clone()].iter().any(|pixel| pixel >> 24 != 0))
+        };
+
+        // Near the east edge of tile 0, its middle still inside
+        let mut owner = DrawTarget::new(size as i32, size as i32);
+        placer(0).place(&mut owner, &geo::Geometry::Point(geo::Point::new(3900.0, 2048.0)), &properties, &style);
+        assert!(drawn(&owner, 40..size), "the label should be drawn in its own tile");
+
+        let mut neighbour = DrawTarget::new(size as i32, size as i32);
+        placer(1).draw_overflow(&mut neighbour);
+        assert!(drawn(&neighbour, 0..8), "the overflow should be drawn on the neighbour's west edge");
+        assert!(!drawn(&neighbour, 48..size), "only the overflow is drawn");
+
+        let mut other = DrawTarget::new(size as i32, size as i32);
+        placer(2).draw_overflow(&mut other);
+        assert!(!drawn(&other, 0..size));
+    }
+}
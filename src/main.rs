@@ -2,19 +2,31 @@ use bevy::{prelude::*, window::PrimaryWindow};
 use bevy_pancam::PanCamPlugin;
 use camera::{camera_middle_to_lat_long, setup_camera};
 use debug::DebugPlugin;
-use ofm_api::OfmTiles;
-use rstar::RTree;
-use tile::{world_mercator_to_lat_lon, Coord};
-use tile_map::{ChunkManager, Location, TileMapPlugin, ZoomManager};
+use projection::LonLat;
+use tile_map::{prefetch_settings_from_args, ChunkManager, Location, TileMapPlugin, ZoomManager};
+use style::style_from_args;
+use tile_source::source_from_args;
+use worker_pool::worker_settings_from_args;
 
 pub mod ofm_api;
-pub mod tile;
+pub mod projection;
 pub mod tile_map;
 pub mod debug;
 pub mod camera;
+pub mod tile_source;
+pub mod tile_cache;
+pub mod tilejson;
+pub mod pmtiles;
+pub mod mbtiles;
+pub mod style;
+pub mod maplibre_style;
+pub mod labels;
+pub mod error;
+pub mod compression;
+pub mod http;
+pub mod worker_pool;
 
-pub const STARTING_LONG_LAT: Coord = Coord::new(0.011, 0.011);
-pub const STARTING_DISPLACEMENT: Coord = Coord::new(52.207_59, 0.186_745_48);
+pub const STARTING_DISPLACEMENT: LonLat = LonLat::new(0.186_745_48, 52.207_59);
 pub const TILE_QUALITY: i32 = 512;
 
 fn main() {
@@ -29,11 +41,12 @@ fn main() {
     .add_systems(Startup, setup_camera)
     .add_systems(Update, handle_mouse)
     .insert_resource(Location::default())
+    // After the plugins, so the log is up for any warnings loading these gives
+    .insert_resource(source_from_args(std::env::args().skip(1)))
+    .insert_resource(style_from_args(std::env::args().skip(1)))
+    .insert_resource(worker_settings_from_args(std::env::args().skip(1)))
+    .insert_resource(prefetch_settings_from_args(std::env::args().skip(1)))
     .add_plugins(DebugPlugin)
-    .insert_resource(OfmTiles {
-        tiles: RTree::new(),
-        tiles_to_render: Vec::new(),
-    })
     .insert_resource(ClearColor(Color::from(Srgba { red: 0.1, green: 0.1, blue: 0.1, alpha: 1.0 })))
     .run();
 }
@@ -51,20 +64,20 @@ pub fn handle_mouse(
         if let Some(position) = q_windows.single().cursor_position() {
             /*
             let world_pos = camera.viewport_to_world_2d(camera_transform, position).unwrap();
-            let long_lat = world_mercator_to_lat_lon(world_pos.x as f64, world_pos.y as f64, chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size);
-            let closest_tile = long_lat.to_tile_coords(zoom_manager.zoom_level).to_lat_long();
+            let long_lat = chunk_manager.world_space(&zoom_manager).world_to_lon_lat(world_pos);
+            let closest_tile = long_lat.to_tile(zoom_manager.zoom_level).to_lon_lat();
             info!("{:?}", closest_tile);
             */
 
             let world_pos = camera.viewport_to_world_2d(camera_transform, position).unwrap();
-            info!("{:?}", world_mercator_to_lat_lon(world_pos.x.into(), world_pos.y.into(), chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size));
+            info!("{:?}", chunk_manager.world_space(&zoom_manager).world_to_lon_lat(world_pos));
         }
     }   
     if buttons.pressed(MouseButton::Middle){
         chunk_manager.update = true;
     }
     if buttons.just_released(MouseButton::Middle) {
-        let movement = camera_middle_to_lat_long(camera_transform, zoom_manager.zoom_level, zoom_manager.tile_size, chunk_manager.refrence_long_lat);
+        let movement = camera_middle_to_lat_long(camera_transform, &chunk_manager.world_space(&zoom_manager));
         if movement != location_manager.location {
             location_manager.location = movement;
             chunk_manager.update = true;
@@ -0,0 +1,496 @@
+use serde_json::{Map, Value};
+
+use crate::style::{Filter, Paint, PropertyValue, Style, StyleColor, StyleLayer, ZoomValue};
+
+// Spec: https://maplibre.org/maplibre-style-spec/
+/// Whether a JSON document looks like a MapLibre/Mapbox GL style rather than one of ours
+pub fn is_maplibre_style(json: &Value) -> bool {
+    json.get("version").is_some() && json.get("sources").is_some()
+}
+
+/// Converts the supported subset of a MapLibre style into a [`Style`].
+/// Anything that can't be represented is skipped and described in the returned warnings.
+pub fn import_maplibre_style(json: &Value) -> (Style, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut style = Style {
+        background: None,
+        layers: Vec::new(),
+    };
+
+    let layers = json.get("layers").and_then(Value::as_array).cloned().unwrap_or_default();
+    for layer in layers.iter().filter_map(Value::as_object) {
+        let id = layer.get("id").and_then(Value::as_str).unwrap_or("<unnamed>").to_string();
+        let layer_type = layer.get("type").and_then(Value::as_str).unwrap_or_default();
+        let paint = layer.get("paint").and_then(Value::as_object).cloned().unwrap_or_default();
+
+        if layer.get("layout").and_then(|layout| layout.get("visibility")).and_then(Value::as_str) == Some("none") {
+            continue;
+        }
+
+        let mut context = LayerContext { id: &id, paint: &paint, warnings: &mut warnings };
+        let paint = match layer_type {
+            "background" => {
+                let color = context.color("background-color");
+                let opacity = context.number("background-opacity").at(0.0);
+                style.background = color.map(|color| StyleColor { a: (color.a as f32 * opacity) as u8, ..color });
+                context.warn_unused(&["background-color", "background-opacity"]);
+                continue;
+            }
+            "fill" => {
+                let Some(color) = context.color("fill-color") else {
+                    continue;
+                };
+                context.warn_unused(&["fill-color", "fill-opacity"]);
+                Paint::Fill {
+                    color,
+                    opacity: context.number("fill-opacity"),
+                }
+            }
+            "line" => {
+                let Some(color) = context.color("line-color") else {
+                    continue;
+                };
+                let width = context.number("line-width");
+                // Dashes are given in line widths, take the width where the layer is most often seen
+                let dash_scale = width.at(14.0);
+                let dash = paint
+                    .get("line-dasharray")
+                    .and_then(Value::as_array)
+                    .map(|dash| dash.iter().filter_map(Value::as_f64).map(|length| length as f32 * dash_scale).collect())
+                    .unwrap_or_default();
+                context.warn_unused(&["line-color", "line-width", "line-opacity", "line-dasharray"]);
+                Paint::Line {
+                    color,
+                    width,
+                    opacity: context.number("line-opacity"),
+                    dash,
+                }
+            }
+            "circle" => {
+                let Some(color) = context.color("circle-color") else {
+                    continue;
+                };
+                context.warn_unused(&["circle-color", "circle-radius", "circle-opacity"]);
+                Paint::Circle {
+                    color,
+                    radius: context.number("circle-radius"),
+                    opacity: context.number("circle-opacity"),
+                }
+            }
+            "symbol" => {
+                let layout = layer.get("layout").and_then(Value::as_object);
+                let text = layout.and_then(|layout| layout.get("text-field")).map(text_fields).unwrap_or_default();
+                if text.is_empty() {
+                    context.warnings.push(format!("layer {}: symbol layers without a text-field are not supported", id));
+                    continue;
+                }
+                let size = layout.and_then(|layout| layout.get("text-size")).and_then(parse_zoom_value);
+                context.warn_unused(&["text-color", "text-halo-color", "text-halo-width"]);
+                Paint::Symbol {
+                    text,
+                    // Black is the default text colour of the specification
+                    color: context.color("text-color").unwrap_or(StyleColor { r: 0, g: 0, b: 0, a: 0xff }),
+                    halo: context.color("text-halo-color"),
+                    size: size.unwrap_or(ZoomValue::Constant(16.0)),
+                }
+            }
+            other => {
+                warnings.push(format!("layer {}: {} layers are not supported", id, other));
+                continue;
+            }
+        };
+
+        let Some(source_layer) = layer.get("source-layer").and_then(Value::as_str) else {
+            warnings.push(format!("layer {}: has no source-layer", id));
+            continue;
+        };
+        let filter = match layer.get("filter") {
+            None => Filter::Always,
+            Some(filter) => match parse_filter(filter) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    warnings.push(format!("layer {}: unsupported filter {}, skipping the layer", id, e));
+                    continue;
+                }
+            },
+        };
+
+        // Our zoom bounds are whole levels and both inclusive, MapLibre's maxzoom is exclusive
+        let min_zoom = layer.get("minzoom").and_then(Value::as_f64).map(|zoom| zoom.ceil().max(0.0) as u32);
+        let max_zoom = match layer.get("maxzoom").and_then(Value::as_f64) {
+            Some(zoom) if zoom <= 0.0 => {
+                warnings.push(format!("layer {}: maxzoom {} hides it at every zoom, skipping the layer", id, zoom));
+                continue;
+            }
+            zoom => zoom.map(|zoom| zoom.ceil() as u32 - 1),
+        };
+
+        style.layers.push(StyleLayer {
+            id,
+            source_layer: source_layer.to_string(),
+            filter,
+            min_zoom,
+            max_zoom,
+            sort_key: layer
+                .get("layout")
+                .and_then(|layout| layout.get(format!("{}-sort-key", layer_type)))
+                .and_then(property_key),
+            paint,
+        });
+    }
+
+    (style, warnings)
+}
+
+struct LayerContext<'a> {
+    id: &'a str,
+    paint: &'a Map<String, Value>,
+    warnings: &'a mut Vec<String>,
+}
+
+impl LayerContext<'_> {
+    fn warn_unused(&mut self, supported: &[&str]) {
+        for key in self.paint.keys().filter(|key| !supported.contains(&key.as_str())) {
+            self.warnings.push(format!("layer {}: paint property {} is not supported", self.id, key));
+        }
+    }
+
+    fn color(&mut self, key: &str) -> Option<StyleColor> {
+        let value = self.paint.get(key)?;
+        let color = match value {
+            Value::String(color) => parse_color(color),
+            // Colours that change with zoom are flattened to their last stop
+            Value::Object(function) => function
+                .get("stops")
+                .and_then(Value::as_array)
+                .and_then(|stops| stops.last())
+                .and_then(|stop| stop.get(1))
+                .and_then(Value::as_str)
+                .and_then(parse_color),
+            _ => None,
+        };
+        if color.is_none() {
+            self.warnings.push(format!("layer {}: unsupported {} {}", self.id, key, value));
+        }
+        color
+    }
+
+    fn number(&mut self, key: &str) -> ZoomValue {
+        let Some(value) = self.paint.get(key) else {
+            return ZoomValue::Constant(1.0);
+        };
+        match parse_zoom_value(value) {
+            Some(value) => value,
+            None => {
+                self.warnings.push(format!("layer {}: unsupported {} {}", self.id, key, value));
+                ZoomValue::Constant(1.0)
+            }
+        }
+    }
+}
+
+/// Handles plain numbers, `{"base": .., "stops": [..]}` functions and
+/// `["interpolate", ["linear" | "exponential", base], ["zoom"], z0, v0, ..]` expressions
+fn parse_zoom_value(value: &Value) -> Option<ZoomValue> {
+    match value {
+        Value::Number(number) => Some(ZoomValue::Constant(number.as_f64()? as f32)),
+        Value::Object(function) => {
+            let base = function.get("base").and_then(Value::as_f64).unwrap_or(1.0) as f32;
+            let stops = function
+                .get("stops")?
+                .as_array()?
+                .iter()
+                .map(|stop| Some((stop.get(0)?.as_f64()? as f32, stop.get(1)?.as_f64()? as f32)))
+                .collect::<Option<Vec<_>>>()?;
+            Some(ZoomValue::Stops { base, stops })
+        }
+        Value::Array(expression) => {
+            let (operator, rest) = expression.split_first()?;
+            if operator.as_str()? != "interpolate" || rest.len() < 2 || rest[1] != Value::Array(vec!["zoom".into()]) {
+                return None;
+            }
+            let interpolation = rest[0].as_array()?;
+            let base = match interpolation.first()?.as_str()? {
+                "linear" => 1.0,
+                "exponential" => interpolation.get(1)?.as_f64()? as f32,
+                _ => return None,
+            };
+            let stops = rest[2..]
+                .chunks(2)
+                .map(|pair| Some((pair.first()?.as_f64()? as f32, pair.get(1)?.as_f64()? as f32)))
+                .collect::<Option<Vec<_>>>()?;
+            Some(ZoomValue::Stops { base, stops })
+        }
+        _ => None,
+    }
+}
+
+/// Supports `==`, `!=`, `in`, `!in`, `has`, `!has`, `all`, `any` and `!`, written either with a bare
+/// property name (`["==", "class", "river"]`) or as an expression (`["==", ["get", "class"], "river"]`)
+pub fn parse_filter(filter: &Value) -> Result<Filter, String> {
+    let Some((operator, arguments)) = filter.as_array().and_then(|filter| filter.split_first()) else {
+        return match filter {
+            Value::Bool(true) => Ok(Filter::Always),
+            Value::Bool(false) => Ok(Filter::Not(Box::new(Filter::Always))),
+            _ => Err(filter.to_string()),
+        };
+    };
+    let operator = operator.as_str().ok_or_else(|| filter.to_string())?;
+    let key = || arguments.first().and_then(property_key).ok_or_else(|| filter.to_string());
+    let value = |index: usize| arguments.get(index).and_then(property_value).ok_or_else(|| filter.to_string());
+    let values = || -> Result<Vec<PropertyValue>, String> {
+        // Expressions put the candidates in a literal array, legacy filters list them inline
+        match arguments.get(1) {
+            Some(Value::Array(literal)) if literal.first() == Some(&Value::from("literal")) => {
+                literal.get(1).and_then(Value::as_array).into_iter().flatten().map(|value| property_value(value).ok_or_else(|| filter.to_string())).collect()
+            }
+            _ => arguments.get(1..).unwrap_or_default().iter().map(|value| property_value(value).ok_or_else(|| filter.to_string())).collect(),
+        }
+    };
+    let nested = || arguments.iter().map(parse_filter).collect::<Result<Vec<_>, _>>();
+
+    Ok(match operator {
+        "==" => Filter::Eq(key()?, value(1)?),
+        "!=" => Filter::NotEq(key()?, value(1)?),
+        "in" => Filter::In(key()?, values()?),
+        "!in" => Filter::Not(Box::new(Filter::In(key()?, values()?))),
+        "has" => Filter::Has(key()?),
+        "!has" => Filter::Not(Box::new(Filter::Has(key()?))),
+        "all" => Filter::All(nested()?),
+        "any" => Filter::Any(nested()?),
+        "!" => Filter::Not(Box::new(parse_filter(arguments.first().ok_or_else(|| filter.to_string())?)?)),
+        _ => return Err(filter.to_string()),
+    })
+}
+
+/// The properties a `text-field` reads, from either `"{name:latin} {name:nonlatin}"` or an
+/// expression such as `["coalesce", ["get", "name:en"], ["get", "name"]]`
+fn text_fields(field: &Value) -> Vec<String> {
+    match field {
+        Value::String(template) => template
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}').map(|(name, _)| name.to_string()))
+            .collect(),
+        Value::Array(expression) if expression.first() == Some(&Value::from("get")) => {
+            expression.get(1).and_then(Value::as_str).map(|name| vec![name.to_string()]).unwrap_or_default()
+        }
+        Value::Array(expression) => expression.iter().flat_map(text_fields).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `"class"`, `["get", "class"]` and `["geometry-type"]`, which maps to the `$type` pseudo property
+fn property_key(key: &Value) -> Option<String> {
+    match key {
+        Value::String(key) => Some(key.clone()),
+        Value::Array(expression) => match expression.first()?.as_str()? {
+            "get" => Some(expression.get(1)?.as_str()?.to_string()),
+            "geometry-type" => Some("$type".to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn property_value(value: &Value) -> Option<PropertyValue> {
+    match value {
+        Value::Bool(value) => Some(PropertyValue::Bool(*value)),
+        Value::Number(value) => Some(PropertyValue::Number(value.as_f64()?)),
+        Value::String(value) => Some(PropertyValue::String(value.clone())),
+        Value::Null => Some(PropertyValue::Null),
+        _ => None,
+    }
+}
+
+/// Handles `#rgb`, `#rrggbb`, `#rrggbbaa`, `rgb()`, `rgba()`, `hsl()` and `hsla()`
+pub fn parse_color(color: &str) -> Option<StyleColor> {
+    let color = color.trim();
+    if let Some(hex) = color.strip_prefix('#') {
+        if hex.len() == 3 {
+            let expanded: String = hex.chars().flat_map(|c| [c, c]).collect();
+            return StyleColor::try_from(format!("#{}", expanded)).ok();
+        }
+        return StyleColor::try_from(color.to_string()).ok();
+    }
+
+    let (function, arguments) = color.strip_suffix(')')?.split_once('(')?;
+    let arguments: Vec<f32> = arguments
+        .split(',')
+        .map(|argument| argument.trim().trim_end_matches('%').parse().ok())
+        .collect::<Option<_>>()?;
+    let alpha = |index: usize| (arguments.get(index).copied().unwrap_or(1.0).clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    match (function.trim(), arguments.as_slice()) {
+        ("rgb" | "rgba", [r, g, b, ..]) => Some(StyleColor { r: *r as u8, g: *g as u8, b: *b as u8, a: alpha(3) }),
+        ("hsl" | "hsla", [h, s, l, ..]) => {
+            let (r, g, b) = hsl_to_rgb(*h, s / 100.0, l / 100.0);
+            Some(StyleColor { r, g, b, a: alpha(3) })
+        }
+        _ => None,
+    }
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (u8, u8, u8) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f32| ((value + m).clamp(0.0, 1.0) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn string(text: &str) -> PropertyValue {
+        PropertyValue::String(text.to_string())
+    }
+
+    #[test]
+    fn filters_in_both_syntaxes() {
+        let cases = [
+            (json!(["==", "class", "river"]), Filter::Eq("class".to_string(), string("river"))),
+            (json!(["==", ["get", "class"], "river"]), Filter::Eq("class".to_string(), string("river"))),
+            (json!(["!=", "admin_level", 2]), Filter::NotEq("admin_level".to_string(), PropertyValue::Number(2.0))),
+            (json!(["!=", ["get", "admin_level"], 2]), Filter::NotEq("admin_level".to_string(), PropertyValue::Number(2.0))),
+            (json!(["in", "class", "river", "canal"]), Filter::In("class".to_string(), vec![string("river"), string("canal")])),
+            (json!(["in", ["get", "class"], ["literal", ["river", "canal"]]]), Filter::In("class".to_string(), vec![string("river"), string("canal")])),
+            (json!(["!in", "class", "river"]), Filter::Not(Box::new(Filter::In("class".to_string(), vec![string("river")])))),
+            (
+                json!(["!in", ["get", "class"], ["literal", ["river"]]]),
+                Filter::Not(Box::new(Filter::In("class".to_string(), vec![string("river")]))),
+            ),
+            (json!(["has", "name"]), Filter::Has("name".to_string())),
+            (json!(["!has", "name"]), Filter::Not(Box::new(Filter::Has("name".to_string())))),
+            (json!(["==", "$type", "Polygon"]), Filter::Eq("$type".to_string(), string("Polygon"))),
+            (json!(["==", ["geometry-type"], "Polygon"]), Filter::Eq("$type".to_string(), string("Polygon"))),
+            (
+                json!(["all", ["==", "class", "river"], ["has", "name"]]),
+                Filter::All(vec![Filter::Eq("class".to_string(), string("river")), Filter::Has("name".to_string())]),
+            ),
+            (
+                json!(["any", ["==", ["get", "class"], "river"], ["!", ["has", "name"]]]),
+                Filter::Any(vec![
+                    Filter::Eq("class".to_string(), string("river")),
+                    Filter::Not(Box::new(Filter::Has("name".to_string()))),
+                ]),
+            ),
+            (json!(true), Filter::Always),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(parse_filter(&filter), Ok(expected), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn unsupported_filters_are_errors() {
+        for filter in [json!([">=", "rank", 3]), json!(["==", ["zoom"], 3]), json!("class"), json!(["in", "class", ["a"]])] {
+            assert!(parse_filter(&filter).is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn zoom_values_from_stops_and_interpolate() {
+        assert_eq!(parse_zoom_value(&json!(2.5)), Some(ZoomValue::Constant(2.5)));
+
+        let stops = parse_zoom_value(&json!({"base": 1.2, "stops": [[5, 1], [14, 4]]})).unwrap();
+        assert_eq!(stops, ZoomValue::Stops { base: 1.2, stops: vec![(5.0, 1.0), (14.0, 4.0)] });
+        let default_base = parse_zoom_value(&json!({"stops": [[5, 1], [14, 4]]})).unwrap();
+        assert_eq!(default_base, ZoomValue::Stops { base: 1.0, stops: vec![(5.0, 1.0), (14.0, 4.0)] });
+
+        let linear = parse_zoom_value(&json!(["interpolate", ["linear"], ["zoom"], 10, 2, 20, 12])).unwrap();
+        assert_eq!(linear, ZoomValue::Stops { base: 1.0, stops: vec![(10.0, 2.0), (20.0, 12.0)] });
+        assert_eq!(linear.at(5.0), 2.0);
+        assert_eq!(linear.at(15.0), 7.0);
+        assert_eq!(linear.at(25.0), 12.0);
+
+        let exponential = parse_zoom_value(&json!(["interpolate", ["exponential", 1.5], ["zoom"], 10, 2, 20, 12])).unwrap();
+        assert_eq!(exponential, ZoomValue::Stops { base: 1.5, stops: vec![(10.0, 2.0), (20.0, 12.0)] });
+
+        for unsupported in [json!(["step", ["zoom"], 1, 10, 2]), json!(["interpolate", ["linear"], ["get", "rank"], 1, 2]), json!("2")] {
+            assert_eq!(parse_zoom_value(&unsupported), None, "{}", unsupported);
+        }
+    }
+
+    #[test]
+    fn colors() {
+        let color = |r, g, b, a| Some(StyleColor { r, g, b, a });
+        assert_eq!(parse_color("#f80"), color(0xff, 0x88, 0x00, 0xff));
+        assert_eq!(parse_color("#ff8000"), color(0xff, 0x80, 0x00, 0xff));
+        assert_eq!(parse_color("#ff800080"), color(0xff, 0x80, 0x00, 0x80));
+        assert_eq!(parse_color("rgb(255, 128, 0)"), color(255, 128, 0, 0xff));
+        assert_eq!(parse_color("rgba(255, 128, 0, 0.5)"), color(255, 128, 0, 128));
+        assert_eq!(parse_color("hsl(0, 100%, 50%)"), color(255, 0, 0, 0xff));
+        assert_eq!(parse_color("hsl(120, 100%, 25%)"), color(0, 128, 0, 0xff));
+        assert_eq!(parse_color("hsla(240, 100%, 50%, 0.25)"), color(0, 0, 255, 64));
+        for unsupported in ["red", "#ff80", "rgb(255, 128)", "hwb(0, 0%, 0%)"] {
+            assert_eq!(parse_color(unsupported), None, "{}", unsupported);
+        }
+    }
+
+    #[test]
+    fn text_fields_from_templates_and_expressions() {
+        assert_eq!(text_fields(&json!("{name:latin} {name:nonlatin}")), ["name:latin", "name:nonlatin"]);
+        assert_eq!(text_fields(&json!(["coalesce", ["get", "name:en"], ["get", "name"]])), ["name:en", "name"]);
+        assert!(text_fields(&json!("plain text")).is_empty());
+    }
+
+    fn import(layers: Value) -> (Style, Vec<String>) {
+        import_maplibre_style(&json!({ "version": 8, "sources": {}, "layers": layers }))
+    }
+
+    #[test]
+    fn zoom_bounds_become_whole_inclusive_levels() {
+        let layer = |id: &str, minzoom: f64, maxzoom: f64| {
+            json!({ "id": id, "type": "fill", "source-layer": "water", "minzoom": minzoom, "maxzoom": maxzoom, "paint": { "fill-color": "#000" } })
+        };
+        let (style, warnings) = import(json!([layer("whole", 5.0, 14.0), layer("fractional", 5.5, 14.5), layer("hidden", 0.0, 0.0)]));
+        let bounds: Vec<_> = style.layers.iter().map(|layer| (layer.id.as_str(), layer.min_zoom, layer.max_zoom)).collect();
+        // maxzoom 14 stops before level 14, 14.5 still shows part of it
+        assert_eq!(bounds, [("whole", Some(5), Some(13)), ("fractional", Some(6), Some(14))]);
+        assert!(style.layers[0].visible_at(13) && !style.layers[0].visible_at(14));
+        assert!(style.layers[1].visible_at(14) && !style.layers[1].visible_at(15));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("hidden"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn warns_about_what_it_skips() {
+        let (style, warnings) = import(json!([
+            { "id": "sky", "type": "background", "paint": { "background-color": "#102030" } },
+            { "id": "hillshade", "type": "raster", "source": "dem" },
+            { "id": "buildings", "type": "fill-extrusion", "source-layer": "building" },
+            { "id": "park", "type": "fill", "source-layer": "park", "paint": { "fill-color": "#00ff00", "fill-pattern": "trees" } },
+            { "id": "road", "type": "line", "source-layer": "transportation", "filter": [">", "rank", 2], "paint": { "line-color": "#fff" } },
+            { "id": "icons", "type": "symbol", "source-layer": "poi", "layout": { "icon-image": "dot" } },
+            { "id": "hidden", "type": "raster", "layout": { "visibility": "none" } },
+        ]));
+
+        assert_eq!(style.background, Some(StyleColor { r: 0x10, g: 0x20, b: 0x30, a: 0xff }));
+        let ids: Vec<_> = style.layers.iter().map(|layer| layer.id.as_str()).collect();
+        assert_eq!(ids, ["park"]);
+        let expected = [
+            "layer hillshade: raster layers are not supported",
+            "layer buildings: fill-extrusion layers are not supported",
+            "layer park: paint property fill-pattern is not supported",
+            "layer road: unsupported filter",
+            "layer icons: symbol layers without a text-field are not supported",
+        ];
+        assert_eq!(warnings.len(), expected.len(), "{:?}", warnings);
+        for (warning, expected) in warnings.iter().zip(expected) {
+            assert!(warning.starts_with(expected), "{} should start with {}", warning, expected);
+        }
+    }
+}
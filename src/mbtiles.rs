@@ -0,0 +1,173 @@
+use std::{io, path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::{error::TileError, projection::LonLat, tile_source::{TileSource, TileSourceMetadata}};
+
+// Spec: https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+/// Serves tiles out of an MBTiles SQLite database
+pub struct MbTilesSource {
+    connection: Mutex<Connection>,
+    metadata: TileSourceMetadata,
+}
+
+impl MbTilesSource {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let metadata = read_metadata(&connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            metadata,
+        })
+    }
+
+    pub fn get_tile(&self, x: u64, y: u64, zoom: u64) -> rusqlite::Result<Option<Vec<u8>>> {
+        // MBTiles rows use the TMS scheme, which counts from the bottom of the map
+        let Some(tms_y) = (1u64 << zoom).checked_sub(y + 1) else {
+            return Ok(None);
+        };
+        // Usually gzipped, which is undone along with every other source's compression before decoding
+        self.connection.lock().unwrap().query_row(
+            "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+            params![zoom as i64, x as i64, tms_y as i64],
+            |row| row.get(0),
+        ).optional()
+    }
+}
+
+impl TileSource for MbTilesSource {
+    fn fetch(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, TileError> {
+        match self.get_tile(x, y, zoom) {
+            Ok(tile) => Ok(tile.unwrap_or_default()),
+            Err(e) => Err(TileError::CacheIo(io::Error::other(e))),
+        }
+    }
+
+    fn metadata(&self) -> TileSourceMetadata {
+        self.metadata.clone()
+    }
+}
+
+fn read_metadata(connection: &Connection) -> rusqlite::Result<TileSourceMetadata> {
+    let mut metadata = TileSourceMetadata::default();
+    let mut statement = connection.prepare("SELECT name, value FROM metadata")?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+    for row in rows {
+        let (name, value) = row?;
+        let numbers: Vec<f64> = value.split(',').filter_map(|part| part.trim().parse().ok()).collect();
+        match (name.as_str(), numbers.as_slice()) {
+            ("minzoom", [zoom]) => metadata.min_zoom = *zoom as u32,
+            ("maxzoom", [zoom]) => metadata.max_zoom = *zoom as u32,
+            ("bounds", [west, south, east, north]) => metadata.bounds = Some([*west, *south, *east, *north]),
+            ("center", [long, lat, zoom]) => {
+                metadata.center = Some(LonLat::new(*long, *lat));
+                metadata.center_zoom = Some(*zoom as u32);
+            }
+            ("center", [long, lat]) => metadata.center = Some(LonLat::new(*long, *lat)),
+            ("attribution", _) => metadata.attribution = Some(value),
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::PathBuf};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::compression::decompress_tile;
+
+    /// A fresh MBTiles file under the system's temporary directory, holding `tiles` as zoom, column, TMS row and data
+    fn mbtiles(name: &str, metadata: &[(&str, &str)], tiles: &[(u64, u64, u64, &[u8])]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bevy-ofm-viewer-{}-{}.mbtiles", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE metadata (name TEXT, value TEXT);
+                 CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);",
+            )
+            .unwrap();
+        for (name, value) in metadata {
+            connection.execute("INSERT INTO metadata VALUES (?1, ?2)", params![name, value]).unwrap();
+        }
+        for (zoom, column, row, data) in tiles {
+            connection
+                .execute("INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)", params![*zoom as i64, *column as i64, *row as i64, data])
+                .unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn rows_count_from_the_bottom() {
+        let path = mbtiles("rows", &[], &[(1, 0, 1, b"north"), (1, 0, 0, b"south"), (2, 3, 0, b"corner")]);
+        let source = MbTilesSource::open(&path).unwrap();
+        assert_eq!(source.get_tile(0, 0, 1).unwrap().as_deref(), Some(b"north".as_slice()));
+        assert_eq!(source.get_tile(0, 1, 1).unwrap().as_deref(), Some(b"south".as_slice()));
+        assert_eq!(source.get_tile(3, 3, 2).unwrap().as_deref(), Some(b"corner".as_slice()));
+        assert_eq!(source.get_tile(1, 0, 1).unwrap(), None);
+        // Rows past the bottom of the map have no TMS row
+        assert_eq!(source.get_tile(0, 2, 1).unwrap(), None);
+        assert_eq!(source.get_tile(0, u64::MAX - 1, 1).unwrap(), None);
+        assert_eq!(source.fetch(0, 2, 1).unwrap(), Vec::<u8>::new());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn gzipped_tiles_come_out_as_stored() {
+        // A layer field, as plain vector tiles start
+        let tile = b"\x1a\x02\x78\x01".to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tile).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let path = mbtiles("gzip", &[], &[(0, 0, 0, &gzipped)]);
+        let source = MbTilesSource::open(&path).unwrap();
+        let fetched = source.fetch(0, 0, 0).unwrap();
+        assert_eq!(fetched, gzipped);
+        assert_eq!(decompress_tile(fetched).unwrap(), tile);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_metadata() {
+        let path = mbtiles(
+            "metadata",
+            &[
+                ("name", "Cambridge"),
+                ("minzoom", "2"),
+                ("maxzoom", "12"),
+                ("bounds", "-0.5, 51.9,0.5,52.5"),
+                ("center", "0.12,52.2,9"),
+                ("attribution", "<a href=\"https://openstreetmap.org\">OpenStreetMap</a>"),
+            ],
+            &[],
+        );
+        let metadata = MbTilesSource::open(&path).unwrap().metadata();
+        assert_eq!(
+            metadata,
+            TileSourceMetadata {
+                min_zoom: 2,
+                max_zoom: 12,
+                bounds: Some([-0.5, 51.9, 0.5, 52.5]),
+                center: Some(LonLat::new(0.12, 52.2)),
+                center_zoom: Some(9),
+                attribution: Some("<a href=\"https://openstreetmap.org\">OpenStreetMap</a>".to_string()),
+            }
+        );
+        fs::remove_file(&path).unwrap();
+
+        // A center without a zoom, and values that don't parse are left at their defaults
+        let path = mbtiles("partial", &[("center", "0.12,52.2"), ("maxzoom", "high"), ("bounds", "1,2,3")], &[]);
+        let metadata = MbTilesSource::open(&path).unwrap().metadata();
+        let defaults = TileSourceMetadata::default();
+        assert_eq!((metadata.center, metadata.center_zoom), (Some(LonLat::new(0.12, 52.2)), None));
+        assert_eq!((metadata.max_zoom, metadata.bounds), (defaults.max_zoom, None));
+        fs::remove_file(&path).unwrap();
+    }
+}
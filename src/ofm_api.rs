@@ -1,69 +1,169 @@
-use std::{fs, path::Path};
+use std::collections::{BTreeMap, HashMap};
 
-use bevy::{asset::RenderAssetUsages, ecs::system::Resource, image::Image, render::render_resource::{Extent3d, TextureDimension, TextureFormat}};
-use mvt_reader::Reader;
-use raqote::{AntialiasMode, DrawOptions, DrawTarget, PathBuilder, SolidSource, Source, StrokeStyle};
+use bevy::{asset::{Handle, RenderAssetUsages}, ecs::system::Resource, image::Image, render::render_resource::{Extent3d, TextureDimension, TextureFormat}};
+use mvt_reader::{feature::Feature, Reader};
+use raqote::{AntialiasMode, DrawOptions, DrawTarget, Path, PathBuilder, Point, SolidSource, Source, StrokeStyle, Winding};
 use rstar::{RTree, RTreeObject, AABB};
 
-use crate::tile::{level_to_tile_width, Coord};
+use crate::{compression::decompress_tile, error::TileError, labels::{LabelIndex, LabelPlacer, LabelStyle}, style::{Paint, Properties, PropertyValue, Style, ZoomValue}, projection::tiles_across, tile_source::TileSource};
 
-#[derive(Resource, Clone)]
+// How much rasterized tile data is kept in memory for when an area is visited again
+const IMAGE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Style version, zoom, x and y of a rasterized tile
+pub type TileImageKey = (u64, u32, u64, u64);
+
+/// Rasterized tiles kept in memory, so panning back over an area doesn't draw it again.
+/// Once they're over budget the least recently used are dropped.
+/// `tiles` indexes them by where they are, to find a loaded tile covering a spot at another zoom.
+#[derive(Resource)]
 pub struct OfmTiles {
-    pub tiles: RTree<Tile>,
-    pub tiles_to_render: Vec<Tile>,
+    pub tiles: RTree<CachedTileBounds>,
+    images: HashMap<TileImageKey, CachedImage>,
+    by_use: BTreeMap<u64, TileImageKey>,
+    bytes: usize,
+    max_bytes: usize,
+    clock: u64,
 }
 
-#[derive(Clone)]
-pub struct Tile {
-    pub name: String,
-    pub image: Image,
-    pub tile_location: Coord,
-    pub zoom: u32,
-} 
-
-impl PartialEq for Tile {
-    fn eq(&self, other: &Self) -> bool {
-        self.name == other.name && self.zoom == other.zoom && self.tile_location.lat == other.tile_location.lat && self.tile_location.long == other.tile_location.long
-    }
+struct CachedImage {
+    handle: Handle<Image>,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// Where a cached tile is, in the web mercator square scaled to 0..1 with y going down
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedTileBounds {
+    pub key: TileImageKey,
 }
-impl RTreeObject for Tile {
+
+impl RTreeObject for CachedTileBounds {
     type Envelope = AABB<[f64; 2]>;
 
     fn envelope(&self) -> Self::Envelope {
+        let (_, zoom, x, y) = self.key;
+        let size = 1.0 / tiles_across(zoom);
         AABB::from_corners(
-            [self.tile_location.long as f64, self.tile_location.lat as f64],
-            [
-                self.tile_location.long as f64 + level_to_tile_width(self.zoom) as f64,
-                self.tile_location.lat as f64 + level_to_tile_width(self.zoom) as f64,
-            ],
+            [x as f64 * size, y as f64 * size],
+            [(x + 1) as f64 * size, (y + 1) as f64 * size],
         )
     }
 }
-impl Tile {
-    pub fn new(name: String, image: Image, tile_location: Coord, zoom: u32) -> Self {
+
+impl CachedTileBounds {
+    pub fn new(key: TileImageKey) -> Self {
+        Self { key }
+    }
+}
+
+impl Default for OfmTiles {
+    fn default() -> Self {
+        Self::new(IMAGE_CACHE_BYTES)
+    }
+}
+
+impl OfmTiles {
+    pub fn new(max_bytes: usize) -> Self {
         Self {
-            name,
-            image,
-            tile_location,
-            zoom,
+            tiles: RTree::new(),
+            images: HashMap::new(),
+            by_use: BTreeMap::new(),
+            bytes: 0,
+            max_bytes,
+            clock: 0,
         }
     }
+
+    pub fn get(&mut self, key: TileImageKey) -> Option<Handle<Image>> {
+        self.clock += 1;
+        let image = self.images.get_mut(&key)?;
+        self.by_use.remove(&image.last_used);
+        image.last_used = self.clock;
+        self.by_use.insert(self.clock, key);
+        Some(image.handle.clone())
+    }
+
+    pub fn contains(&self, key: TileImageKey) -> bool {
+        self.images.contains_key(&key)
+    }
+
+    /// Returns the tiles dropped to make room, their labels can go too
+    pub fn insert(&mut self, key: TileImageKey, handle: Handle<Image>, bytes: usize) -> Vec<TileImageKey> {
+        self.remove(key);
+        self.clock += 1;
+        self.images.insert(key, CachedImage { handle, bytes, last_used: self.clock });
+        self.by_use.insert(self.clock, key);
+        self.tiles.insert(CachedTileBounds::new(key));
+        self.bytes += bytes;
+
+        let mut evicted = Vec::new();
+        while self.bytes > self.max_bytes {
+            let Some(&oldest) = self.by_use.values().next() else {
+                break;
+            };
+            self.remove(oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: TileImageKey) {
+        if let Some(image) = self.images.remove(&key) {
+            self.by_use.remove(&image.last_used);
+            self.tiles.remove(&CachedTileBounds::new(key));
+            self.bytes -= image.bytes;
+        }
+    }
+
+    /// The closest loaded tile at most `max_levels` above `key` that covers it, with how many levels up it is
+    pub fn ancestor(&self, key: TileImageKey, max_levels: u32) -> Option<(u32, Handle<Image>)> {
+        let (style_version, zoom, x, y) = key;
+        let size = 1.0 / tiles_across(zoom);
+        let center = [(x as f64 + 0.5) * size, (y as f64 + 0.5) * size];
+        self.tiles
+            .locate_in_envelope_intersecting(&AABB::from_point(center))
+            .filter(|tile| tile.key.0 == style_version && tile.key.1 < zoom && zoom - tile.key.1 <= max_levels)
+            .max_by_key(|tile| tile.key.1)
+            .and_then(|tile| Some((zoom - tile.key.1, self.images.get(&tile.key)?.handle.clone())))
+    }
 }
 
-pub fn tile_width_meters(zoom: u32) -> f64 {
-    let earth_circumference_meters = 40075016.686;
-    let num_tiles = 2_u32.pow(zoom) as f64;
-    earth_circumference_meters / num_tiles
+/// Size of a vector tile in its own coordinates
+const TILE_EXTENT: f32 = 4096.0;
+
+/// A tile to draw and what it's drawn from
+#[derive(Clone, Copy)]
+pub struct TileRequest<'a> {
+    pub source: &'a dyn TileSource,
+    pub style: &'a Style,
+    pub labels: &'a LabelIndex,
+    pub x: u64,
+    pub y: u64,
+    pub zoom: u64,
+    pub tile_size: u32,
+    /// The labels it places are marked with this, see `LabelIndex::new_drawing`
+    pub drawing: u64,
 }
 
-pub fn get_ofm_image(x: u64, y: u64, zoom: u64, tile_size: u32) -> Image {
-    let data = send_ofm_request(x, y, zoom);
-    buffer_to_bevy_image(ofm_to_data_image(data, tile_size, zoom as u32), tile_size)
+pub fn get_ofm_image(request: &TileRequest) -> Result<Image, TileError> {
+    Ok(buffer_to_bevy_image(get_ofm_data(request, &|| false)?, request.tile_size))
 }
 
-pub fn get_ofm_data(x: u64, y: u64, zoom: u64, tile_size: u32) -> Vec<u8> {
-    let data = send_ofm_request(x, y, zoom);
-    ofm_to_data_image(data, tile_size, zoom as u32)
+/// `cancelled` is asked before the fetch and again before drawing, so a tile that went out of view stops early
+pub fn get_ofm_data(request: &TileRequest, cancelled: &dyn Fn() -> bool) -> Result<Vec<u8>, TileError> {
+    if cancelled() {
+        return Err(TileError::Cancelled);
+    }
+    let TileRequest { source, x, y, zoom, .. } = *request;
+    // Past the source's last level the tile is cut out of its ancestor on that level
+    let overzoom = zoom.saturating_sub(source.metadata().max_zoom as u64) as u32;
+    // Servers, caches and archives often hand out tiles still compressed
+    let data = decompress_tile(source.fetch(x >> overzoom, y >> overzoom, zoom - overzoom as u64)?)?;
+    if cancelled() {
+        return Err(TileError::Cancelled);
+    }
+    ofm_to_data_image(data, request, overzoom)
 }
 
 pub fn buffer_to_bevy_image(data: Vec<u8>, tile_size: u32) -> Image {
@@ -80,46 +180,19 @@ pub fn buffer_to_bevy_image(data: Vec<u8>, tile_size: u32) -> Image {
     )
 }
 
-fn send_ofm_request(x: u64, y: u64, zoom: u64) -> Vec<u8> {
-    let cache_dir = "cache";
-    let cache_file = format!("{}/{}_{}_{}.pbf", cache_dir, zoom, x, y);
-
-    // Check if the file exists in the cache
-    if Path::new(&cache_file).exists() {
-        return fs::read(&cache_file).expect("Failed to read cache file");
-    }
-
-    // If not in cache, fetch from the network
-    let url = "https://tiles.openfreemap.org/planet/20250122_001001_pt";
-    let mut status = 429;
-    while status == 429 {
-        if let Ok(response) = ureq::get(format!("{}/{}/{}/{}.pbf", url, zoom, x, y).as_str()).call() {
-            if response.status() == 200 {
-                let mut reader = response.into_reader();
-                let mut bytes = Vec::new();
-                reader.read_to_end(&mut bytes).expect("Failed to read bytes from response");
-
-                // Save to cache
-                fs::create_dir_all(cache_dir).expect("Failed to create cache directory");
-                fs::write(&cache_file, &bytes).expect("Failed to write cache file");
-
-                return bytes;
-            } else if response.status() == 429 {
-                std::thread::sleep(std::time::Duration::from_secs(5));
-            } else {
-                status = 0;
-            }
-        }
-    }
-    vec![]
-}
-
-/// This converts it to an image which is as many meters as the tile width This would be AAAMAAZZZING to multithread
-fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Vec<u8> {
-    let tile = Reader::new(data).unwrap();
+/// This converts it to an image which is as many meters as the tile width This would be AAAMAAZZZING to multithread.
+/// With an `overzoom` the data is from `overzoom` levels up, and only the part covering this tile is drawn.
+fn ofm_to_data_image(data: Vec<u8>, request: &TileRequest, overzoom: u32) -> Result<Vec<u8>, TileError> {
+    let TileRequest { style, labels: label_index, x, y, tile_size: size, drawing, .. } = *request;
+    let zoom = request.zoom as u32;
+    let tile = Reader::new(data).map_err(|e| TileError::Decode(format!("{:?}", e)))?;
     //let size_multiplyer = TILE_QUALITY as u32 / size ;
     let mut dt = DrawTarget::new(size as i32 , size as i32);
 
+    if let Some(background) = style.background {
+        dt.clear(background.to_source());
+    }
+
     if cfg!(debug_assertions) {
         let mut pb: PathBuilder = PathBuilder::new();
         pb.move_to(0.0, 0.0);
@@ -154,190 +227,433 @@ fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Vec<u8> {
         );
     }
     
-    let scale = (size as f32 / tile_width_meters(14.try_into().unwrap()).round() as f32) * 0.597_014_9;
-    dt.set_transform(&raqote::Transform::scale(scale, scale));
-
-    // Iterate over layers and features]
-    let layer_names = tile.get_layer_names().unwrap();
-    for (i, title) in layer_names.into_iter().enumerate() {
-        for features in tile.get_features(i).iter() {
-            for feature in features {
-                let mut pb: PathBuilder = PathBuilder::new();
-                match &feature.geometry {
-                    geo::Geometry::Point(point) 
-                        => {
-                            if zoom >= 15 {
-                                pb.move_to(point.x(), point.y());
-                                pb.line_to(point.x() + 1.0, point.y() + 1.0);
-                                pb.line_to(point.x() + 1.0, point.y());
-                                pb.line_to(point.x(), point.y() + 1.0)
-                            }
-                        },
-                    geo::Geometry::Line(line) 
-                        => {
+    // The ancestor is drawn 2^overzoom times the tile size and moved so this tile's part lands on the draw target
+    let scale = size as f32 / TILE_EXTENT * 2_f32.powi(overzoom as i32);
+    let mask = (1_u64 << overzoom) - 1;
+    let origin = Point::new((x & mask) as f32 * size as f32, (y & mask) as f32 * size as f32);
+    dt.set_transform(&raqote::Transform::new(scale, 0.0, 0.0, scale, -origin.x, -origin.y));
+
+    label_index.start_tile(zoom, x, y, drawing);
+    let labels = LabelPlacer {
+        index: label_index,
+        zoom,
+        x,
+        y,
+        size: size as f32,
+        scale,
+        origin,
+    };
+
+    // Decode every layer once, as several style layers can draw from the same one
+    let layer_names = tile.get_layer_names().map_err(|e| TileError::Decode(format!("{:?}", e)))?;
+    let layers: HashMap<String, Vec<Feature>> = layer_names
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, name)| tile.get_features(i).ok().map(|features| (name, features)))
+        .collect();
+
+    for style_layer in style.layers.iter().filter(|layer| layer.visible_at(zoom)) {
+        let Some(features) = layers.get(&style_layer.source_layer) else {
+            continue;
+        };
+        let mut matching: Vec<(&Feature, Properties)> = features
+            .iter()
+            .map(|feature| (feature, feature_properties(feature)))
+            .filter(|(_, properties)| style_layer.filter.matches(properties))
+            .collect();
+
+        // Lets tunnels go under roads and bridges over them within the one layer
+        if let Some(sort_key) = &style_layer.sort_key {
+            let key = |properties: &Properties| properties.get(sort_key).and_then(PropertyValue::as_number).unwrap_or(0.0);
+            matching.sort_by(|(_, a), (_, b)| key(a).total_cmp(&key(b)));
+        }
+
+        let label_style = match &style_layer.paint {
+            Paint::Symbol { text, color, halo, size } => Some(LabelStyle {
+                text_fields: text,
+                font_size: size.at(zoom as f32),
+                color: *color,
+                halo: *halo,
+            }),
+            _ => None,
+        };
+        for (feature, properties) in matching {
+            match &label_style {
+                Some(label_style) => labels.place(&mut dt, &feature.geometry, &properties, label_style),
+                None => draw_feature(&mut dt, &feature.geometry, &style_layer.paint, zoom as f32, scale),
+            }
+        }
+    }
+    labels.draw_overflow(&mut dt);
+
+    Ok(argb_to_rgba(dt.get_data()))
+}
+
+/// Shown in place of a tile that couldn't be loaded, until it is retried
+pub fn failed_tile_data(size: u32) -> Vec<u8> {
+    let mut dt = DrawTarget::new(size as i32, size as i32);
+    dt.clear(SolidSource::from_unpremultiplied_argb(0xff, 0x40, 0x18, 0x18));
+
+    let mut pb = PathBuilder::new();
+    pb.move_to(0.0, 0.0);
+    pb.line_to(size as f32, size as f32);
+    pb.move_to(size as f32, 0.0);
+    pb.line_to(0.0, size as f32);
+    dt.stroke(
+        &pb.finish(),
+        &Source::Solid(SolidSource::from_unpremultiplied_argb(0xff, 0x80, 0x30, 0x30)),
+        &StrokeStyle {
+            width: 4.0,
+            ..Default::default()
+        },
+        &DrawOptions::new(),
+    );
+
+    argb_to_rgba(dt.get_data())
+}
+
+/// raqote stores premultiplied `0xAARRGGBB` words, Bevy wants straight RGBA bytes
+pub fn argb_to_rgba(data: &[u32]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(data.len() * 4);
+    for pixel in data {
+        let [a, r, g, b] = pixel.to_be_bytes();
+        let unpremultiply = |channel: u8| {
+            if a == 0 {
+                0
+            } else {
+                ((channel as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8
+            }
+        };
+        rgba.extend_from_slice(&[unpremultiply(r), unpremultiply(g), unpremultiply(b), a]);
+    }
+    rgba
+}
+
+/// The feature's own properties (road `class`, `brunnel`, `admin_level`, ...) plus its `$type`
+fn feature_properties(feature: &Feature) -> Properties {
+    let mut properties: Properties = feature.properties.iter().flatten().map(|(key, value)| (key.clone(), value.into())).collect();
+    properties.insert("$type".to_string(), PropertyValue::String(geometry_type(&feature.geometry).to_string()));
+    properties
+}
+
+/// The `$type` filters check against, as named by the style specification
+fn geometry_type(geometry: &geo::Geometry<f32>) -> &'static str {
+    match geometry {
+        geo::Geometry::Point(_) | geo::Geometry::MultiPoint(_) => "Point",
+        geo::Geometry::Line(_) | geo::Geometry::LineString(_) | geo::Geometry::MultiLineString(_) => "LineString",
+        _ => "Polygon",
+    }
+}
+
+fn draw_feature(dt: &mut DrawTarget, geometry: &geo::Geometry<f32>, paint: &Paint, zoom: f32, scale: f32) {
+    let options = |opacity: &ZoomValue| DrawOptions {
+        antialias: AntialiasMode::Gray,
+        blend_mode: raqote::BlendMode::SrcOver,
+        alpha: opacity.at(zoom).clamp(0.0, 1.0),
+    };
+
+    // The draw target is scaled to tile units, paint sizes are in pixels
+    match paint {
+        Paint::Fill { color, opacity } => {
+            dt.fill(&geometry_to_path(geometry), &Source::Solid(color.to_source()), &options(opacity));
+        }
+        Paint::Line { color, width, opacity, dash } => {
+            let stroke_style = StrokeStyle {
+                cap: raqote::LineCap::Round,
+                join: raqote::LineJoin::Round,
+                width: width.at(zoom) / scale,
+                miter_limit: 10.0,
+                dash_array: dash.iter().map(|length| length / scale).collect(),
+                dash_offset: 0.0,
+            };
+            dt.stroke(&geometry_to_path(geometry), &Source::Solid(color.to_source()), &stroke_style, &options(opacity));
+        }
+        // Labels need the whole tile to find room for them, see `LabelPlacer`
+        Paint::Symbol { .. } => {}
+        Paint::Circle { color, radius, opacity } => {
+            let radius = radius.at(zoom) / scale;
+            let mut pb = PathBuilder::new();
+            for point in geometry_points(geometry) {
+                pb.move_to(point.x() + radius, point.y());
+                pb.arc(point.x(), point.y(), radius, 0.0, 2.0 * std::f32::consts::PI);
+                pb.close();
+            }
+            dt.fill(&pb.finish(), &Source::Solid(color.to_source()), &options(opacity));
+        }
+    }
+}
+
+fn geometry_points(geometry: &geo::Geometry<f32>) -> Vec<geo::Point<f32>> {
+    match geometry {
+        geo::Geometry::Point(point) => vec![*point],
+        geo::Geometry::MultiPoint(multi_point) => multi_point.iter().copied().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn geometry_to_path(geometry: &geo::Geometry<f32>) -> Path {
+    let mut pb: PathBuilder = PathBuilder::new();
+    match geometry {
+        geo::Geometry::Point(_) | geo::Geometry::MultiPoint(_) => {},
+        geo::Geometry::Line(line) 
+            => {
+                pb.move_to(line.start.x, line.start.y);
+                pb.line_to(line.end.x, line.end.y);
+            },
+        geo::Geometry::LineString(line_string) 
+            => {
+                for (j, line) in line_string.lines().enumerate() {
+                    if j == 0 {
+                        pb.move_to(line.start.x, line.start.y);
+                        pb.line_to(line.end.x, line.end.y);
+                    } else {
+                        pb.line_to(line.start.x, line.start.y);
+                        pb.line_to(line.end.x, line.end.y);
+                    }
+                }
+            },
+        geo::Geometry::Polygon(polygon) => add_polygon(&mut pb, polygon),
+        geo::Geometry::MultiPolygon(multi_polygon) => {
+            for polygon in multi_polygon {
+                add_polygon(&mut pb, polygon);
+            }
+        },
+        geo::Geometry::MultiLineString(multi_line_string) 
+            => {
+                for line_string in multi_line_string {
+                    for (j, line) in line_string.lines().enumerate() {
+                        if j == 0 {
                             pb.move_to(line.start.x, line.start.y);
                             pb.line_to(line.end.x, line.end.y);
-                        },
-                    geo::Geometry::LineString(line_string) 
-                        => {
-                            for (j, line) in line_string.lines().enumerate() {
-                                if j == 0 {
-                                    pb.move_to(line.start.x, line.start.y);
-                                    pb.line_to(line.end.x, line.end.y);
-                                } else {
-                                    pb.line_to(line.start.x, line.start.y);
-                                    pb.line_to(line.end.x, line.end.y);
-                                }
-                            }
-                        },
-                    geo::Geometry::Polygon(polygon) 
-                        => {
-                                for (j, line) in polygon.exterior().0.iter().enumerate() {
-                                    if j == 0 {
-                                        pb.move_to(line.x, line.y);
-                                        pb.line_to(line.x, line.y);
-                                    } else {
-                                        pb.line_to(line.x, line.y);
-                                        pb.line_to(line.x, line.y);
-                                    }
-                                }
-                        },
-                    geo::Geometry::MultiPolygon(multi_polygon)
-                        => {
-                                for polygon in multi_polygon {
-                                    for (j, line) in polygon.exterior().0.iter().enumerate() {
-                                        if j == 0 {
-                                            pb.move_to(line.x, line.y);
-                                            pb.line_to(line.x, line.y);
-                                        } else {
-                                            pb.line_to(line.x, line.y);
-                                            pb.line_to(line.x, line.y);
-                                        }
-                                    }
-                                }
-                        },
-                    geo::Geometry::MultiPoint(multi_point) 
-                        => {
-                            if zoom >= 15 {
-                                for point in multi_point {
-                                    pb.move_to(point.x(), point.y());
-                                    pb.line_to(point.x() + 1.0, point.y() + 1.0);
-                                    pb.line_to(point.x() + 1.0, point.y());
-                                    pb.line_to(point.x(), point.y() + 1.0)
-                                }
-                            }
-                        },
-                    geo::Geometry::MultiLineString(multi_line_string) 
-                        => {
-                            for line_string in multi_line_string {
-                                for (j, line) in line_string.lines().enumerate() {
-                                    if j == 0 {
-                                        pb.move_to(line.start.x, line.start.y);
-                                        pb.line_to(line.end.x, line.end.y);
-                                    } else {
-                                        pb.line_to(line.start.x, line.start.y);
-                                        pb.line_to(line.end.x, line.end.y);
-                                    }
-                                }
-                            }
-                        },
-                    geo::Geometry::GeometryCollection(geometry_collection) => {
-                        println!("GeometryCollection: {:?}", geometry_collection);
-                    },
-                    geo::Geometry::Rect(rect) => {
-                        println!("Rect: {:?}", rect);
-                    },
-                    geo::Geometry::Triangle(triangle) => {
-                        println!("Triangle: {:?}", triangle);
-                    },
+                        } else {
+                            pb.line_to(line.start.x, line.start.y);
+                            pb.line_to(line.end.x, line.end.y);
+                        }
+                    }
                 }
+            },
+        geo::Geometry::GeometryCollection(geometry_collection) => {
+            println!("GeometryCollection: {:?}", geometry_collection);
+        },
+        geo::Geometry::Rect(rect) => {
+            println!("Rect: {:?}", rect);
+        },
+        geo::Geometry::Triangle(triangle) => {
+            println!("Triangle: {:?}", triangle);
+        },
+    }
+    let mut path = pb.finish();
+    // Interior rings are sub-paths of their own, even-odd filling leaves them as holes
+    path.winding = Winding::EvenOdd;
+    path
+}
 
-                if title == "building" {
-                    let path = pb.finish();
-                    dt.fill(
-                        &path,
-                    &Source::Solid(SolidSource {
-                            r: 0xff,
-                            g: 0xff,
-                            b: 0xff,
-                            a: 0xff,
-                        }),        
-                        
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            blend_mode: raqote::BlendMode::SrcOver,
-                            alpha: 0.5,
-                        },
-                    );
-                } else if title == "park" {
-                    let path = pb.finish();
-                    dt.fill(
-                        &path,
-                    &Source::Solid(SolidSource {
-                            r: 0x00,
-                            g: 0xff,
-                            b: 0x00,
-                            a: 0xff,
-                        }),        
-                        
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            blend_mode: raqote::BlendMode::SrcOver,
-                            alpha: 0.5,
-                        },
-                    );
-                } else if title == ("water") {
-                    let path = pb.finish();
-                    dt.fill(
-                        &path,
-                        // For some reason red and blue are swapped
-                    &Source::Solid(SolidSource {
-                            b: 0x00,
-                            g: 0x00,
-                            r: 0xff,
-                            a: 0xff,
-                        }),        
-                        
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            blend_mode: raqote::BlendMode::SrcOver,
-                            alpha: 0.5,
-                        },
-                    );
-                } else if title.contains("water") || title.contains("mountian") || title.contains("land") {
+fn add_polygon(pb: &mut PathBuilder, polygon: &geo::Polygon<f32>) {
+    for ring in std::iter::once(polygon.exterior()).chain(polygon.interiors()) {
+        for (j, point) in ring.0.iter().enumerate() {
+            if j == 0 {
+                pb.move_to(point.x, point.y);
+            } else {
+                pb.line_to(point.x, point.y);
+            }
+        }
+        pb.close();
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile_source::MemorySource;
+
+    // Just enough protobuf to write a vector tile, see https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn field(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+        varint(out, number << 3 | 2);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn zigzag(value: i32) -> u64 {
+        ((value << 1) ^ (value >> 31)) as u32 as u64
+    }
+
+    /// Geometry commands for closed rings, each given by its corners
+    fn polygon_geometry(rings: &[&[(i32, i32)]]) -> Vec<u8> {
+        let mut commands = Vec::new();
+        let mut cursor = (0, 0);
+        for ring in rings {
+            for (i, &(x, y)) in ring.iter().enumerate() {
+                match i {
+                    0 => varint(&mut commands, 1 | 1 << 3),
+                    1 => varint(&mut commands, 2 | (ring.len() as u64 - 1) << 3),
+                    _ => {}
                 }
-                else {
-                    let path = pb.finish();
-
-                    let stroke_style = StrokeStyle {
-                        cap: raqote::LineCap::Round,
-                        join: raqote::LineJoin::Round,
-                        width: 10.,
-                        miter_limit: 10.0,
-                        dash_array: vec![],
-                        dash_offset: 0.0,
-                    };
-                
-                    dt.stroke(
-                        &path,
-                    &Source::Solid(SolidSource {
-                            r: 0xff,
-                            g: 0xff,
-                            b: 0xff,
-                            a: 0xff,
-                        }),        
-                        
-                        &stroke_style,
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            ..Default::default()
-                        },
-                    );
-                }
+                varint(&mut commands, zigzag(x - cursor.0));
+                varint(&mut commands, zigzag(y - cursor.1));
+                cursor = (x, y);
             }
+            varint(&mut commands, 7 | 1 << 3);
         }
+        commands
+    }
+
+    fn polygon_tile(layer_name: &str, rings: &[&[(i32, i32)]]) -> Vec<u8> {
+        let mut feature = Vec::new();
+        // Type 3 is a polygon
+        feature.extend_from_slice(&[3 << 3, 3]);
+        field(&mut feature, 4, &polygon_geometry(rings));
+
+        let mut layer = Vec::new();
+        layer.extend_from_slice(&[15 << 3, 2]);
+        field(&mut layer, 1, layer_name.as_bytes());
+        field(&mut layer, 2, &feature);
+        varint(&mut layer, 5 << 3);
+        varint(&mut layer, TILE_EXTENT as u64);
+
+        let mut tile = Vec::new();
+        field(&mut tile, 3, &layer);
+        tile
+    }
+
+    fn pixel(rgba: &[u8], size: u32, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * size + x) * 4) as usize;
+        rgba[i..i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn image_cache_evicts_least_recently_used() {
+        let mut tiles = OfmTiles::new(300);
+        let key = |x: u64| (0, 14, x, 0);
+        for x in 0..3 {
+            assert!(tiles.insert(key(x), Handle::default(), 100).is_empty());
+        }
+        // Using the oldest makes the second the one to go
+        assert!(tiles.get(key(0)).is_some());
+        assert_eq!(tiles.insert(key(3), Handle::default(), 100), [key(1)]);
+        assert!(!tiles.contains(key(1)) && tiles.contains(key(0)));
+
+        // Putting the same tile in again replaces it rather than counting it twice
+        assert!(tiles.insert(key(3), Handle::default(), 100).is_empty());
+        assert_eq!(tiles.insert(key(4), Handle::default(), 250), [key(2), key(0), key(3)]);
+        assert!(tiles.contains(key(4)));
+        assert_eq!(tiles.tiles.size(), 1, "evicted tiles should leave the R-tree too");
+    }
+
+    #[test]
+    fn ancestor_is_the_nearest_loaded_level() {
+        let mut tiles = OfmTiles::new(usize::MAX);
+        let (parent, grandparent, other_style) = (Handle::weak_from_u128(1), Handle::weak_from_u128(2), Handle::weak_from_u128(3));
+        tiles.insert((1, 10, 100, 200), parent.clone(), 1);
+        tiles.insert((1, 8, 25, 50), grandparent.clone(), 1);
+        tiles.insert((2, 11, 201, 401), other_style, 1);
+
+        assert_eq!(tiles.ancestor((1, 12, 403, 801), 4), Some((2, parent.clone())));
+        assert_eq!(tiles.ancestor((1, 11, 201, 401), 4), Some((1, parent.clone())));
+        // Not the tile itself, the one above it
+        assert_eq!(tiles.ancestor((1, 10, 100, 200), 4), Some((2, grandparent.clone())));
+        assert_eq!(tiles.ancestor((1, 10, 103, 203), 4), Some((2, grandparent.clone())));
+        assert_eq!(tiles.ancestor((1, 12, 403, 801), 1), None);
+        assert_eq!(tiles.ancestor((1, 12, 415, 815), 3), None, "the grandparent is 4 levels up");
+        assert_eq!(tiles.ancestor((1, 12, 415, 815), 4), Some((4, grandparent)));
+        assert_eq!(tiles.ancestor((3, 12, 403, 801), 4), None, "other styles don't count");
+        assert_eq!(tiles.ancestor((1, 12, 0, 0), 4), None);
+
+        tiles.remove((1, 10, 100, 200));
+        assert!(tiles.ancestor((1, 12, 403, 801), 2).is_none());
     }
 
-    dt.get_data_u8().to_vec()
-}
\ No newline at end of file
+    #[test]
+    fn polygon_holes_stay_empty() {
+        // Outer rings go clockwise and holes the other way, with y going down
+        let outer: &[(i32, i32)] = &[(512, 512), (3584, 512), (3584, 3584), (512, 3584)];
+        let hole: &[(i32, i32)] = &[(1536, 1536), (1536, 2560), (2560, 2560), (2560, 1536)];
+        let style: Style = serde_json::from_str(
+            r##"{
+                "background": "#102030",
+                "layers": [{ "id": "water", "source_layer": "water", "paint": { "type": "fill", "color": "#ff8000" } }]
+            }"##,
+        )
+        .unwrap();
+
+        let size = 256;
+        let request = TileRequest {
+            source: &MemorySource::new(),
+            style: &style,
+            labels: &LabelIndex::default(),
+            x: 0,
+            y: 0,
+            zoom: 14,
+            tile_size: size,
+            drawing: 0,
+        };
+        let rgba = ofm_to_data_image(polygon_tile("water", &[outer, hole]), &request, 0).unwrap();
+        assert_eq!(pixel(&rgba, size, 128, 128), [0x10, 0x20, 0x30, 0xff], "the hole");
+        assert_eq!(pixel(&rgba, size, 64, 64), [0xff, 0x80, 0x00, 0xff], "the ring");
+        assert_eq!(pixel(&rgba, size, 128, 48), [0xff, 0x80, 0x00, 0xff], "the ring");
+        assert_eq!(pixel(&rgba, size, 16, 16), [0x10, 0x20, 0x30, 0xff], "outside");
+    }
+
+    #[test]
+    fn overzoomed_tiles_draw_their_part_of_the_ancestor() {
+        // Only the north east quarter of the z14 tile is water
+        let quarter: &[(i32, i32)] = &[(2048, 0), (4096, 0), (4096, 2048), (2048, 2048)];
+        let source = MemorySource::new();
+        source.insert(10, 20, 14, polygon_tile("water", &[quarter]));
+        let style: Style = serde_json::from_str(
+            r##"{
+                "background": "#102030",
+                "layers": [{ "id": "water", "source_layer": "water", "paint": { "type": "fill", "color": "#ff8000" } }]
+            }"##,
+        )
+        .unwrap();
+        let labels = LabelIndex::default();
+
+        let size = 256;
+        let middle = |x: u64, y: u64, zoom: u64| {
+            let request = TileRequest {
+                source: &source,
+                style: &style,
+                labels: &labels,
+                x,
+                y,
+                zoom,
+                tile_size: size,
+                drawing: 0,
+            };
+            pixel(&get_ofm_data(&request, &|| false).unwrap(), size, size / 2, size / 2)
+        };
+        let (water, land) = ([0xff, 0x80, 0x00, 0xff], [0x10, 0x20, 0x30, 0xff]);
+
+        assert_eq!(source.metadata().max_zoom, 14);
+        let children = [(20, 40), (21, 40), (20, 41), (21, 41)].map(|(x, y)| middle(x, y, 15));
+        assert_eq!(children, [land, water, land, land]);
+        // Two levels in, the ancestor is split four ways each way
+        let grandchildren = [(42, 80), (43, 81), (41, 80), (42, 82), (43, 83)].map(|(x, y)| middle(x, y, 16));
+        assert_eq!(grandchildren, [water, water, land, land, land]);
+    }
+
+    #[test]
+    fn argb_to_rgba_unpremultiplies() {
+        let mut dt = DrawTarget::new(3, 1);
+        let fill = |dt: &mut DrawTarget, x: f32, alpha: u8| {
+            let source = Source::Solid(SolidSource::from_unpremultiplied_argb(alpha, 0xff, 0x80, 0x00));
+            dt.fill_rect(x, 0.0, 1.0, 1.0, &source, &DrawOptions::new());
+        };
+        fill(&mut dt, 0.0, 0xff);
+        // Premultiplied that is 0x64, 0x32, 0x00, and 0x32 * 255 / 0x64 rounds back to 0x80
+        fill(&mut dt, 1.0, 0x64);
+        assert_eq!(dt.get_data()[1], 0x6464_3200);
+
+        let rgba = argb_to_rgba(dt.get_data());
+        assert_eq!(rgba, [0xff, 0x80, 0x00, 0xff, 0xff, 0x80, 0x00, 0x64, 0, 0, 0, 0]);
+    }
+}
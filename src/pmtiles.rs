@@ -0,0 +1,406 @@
+use std::{collections::HashMap, fs::File, io::{self, Read, Seek, SeekFrom}, path::Path, sync::Mutex};
+
+use flate2::read::GzDecoder;
+
+use crate::{compression::{decompress_brotli, read_decompressed}, error::TileError, projection::LonLat, tile_source::{TileSource, TileSourceMetadata}};
+
+// Spec: https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+const HEADER_LENGTH: usize = 127;
+const MAX_DIRECTORY_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl From<u8> for Compression {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub root_dir_offset: u64,
+    pub root_dir_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_dirs_offset: u64,
+    pub leaf_dirs_length: u64,
+    pub tile_data_offset: u64,
+    pub tile_data_length: u64,
+    pub internal_compression: Compression,
+    pub tile_compression: Compression,
+    pub tile_type: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+    pub center_zoom: u8,
+    pub center_lon: f64,
+    pub center_lat: f64,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < HEADER_LENGTH || bytes[0..7] != *b"PMTiles" {
+            return Err(invalid_data("not a PMTiles archive"));
+        }
+        if bytes[7] != 3 {
+            return Err(invalid_data(format!("unsupported PMTiles version {}", bytes[7])));
+        }
+
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let e7_at = |at: usize| i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as f64 / 10_000_000.0;
+
+        Ok(Self {
+            root_dir_offset: u64_at(8),
+            root_dir_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_dirs_offset: u64_at(40),
+            leaf_dirs_length: u64_at(48),
+            tile_data_offset: u64_at(56),
+            tile_data_length: u64_at(64),
+            internal_compression: bytes[97].into(),
+            tile_compression: bytes[98].into(),
+            tile_type: bytes[99],
+            min_zoom: bytes[100],
+            max_zoom: bytes[101],
+            min_lon: e7_at(102),
+            min_lat: e7_at(106),
+            max_lon: e7_at(110),
+            max_lat: e7_at(114),
+            center_zoom: bytes[118],
+            center_lon: e7_at(119),
+            center_lat: e7_at(123),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DirEntry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    run_length: u64,
+}
+
+/// Serves tiles out of a single local `.pmtiles` (version 3) archive
+pub struct PmTilesSource {
+    pub header: Header,
+    file: Mutex<File>,
+    /// Nothing the header or a directory points at may reach past this
+    file_length: u64,
+    root: Vec<DirEntry>,
+    leaves: Mutex<HashMap<u64, Vec<DirEntry>>>,
+}
+
+impl PmTilesSource {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header_bytes = [0; HEADER_LENGTH];
+        file.read_exact(&mut header_bytes)?;
+        let header = Header::parse(&header_bytes)?;
+        let file_length = file.metadata()?.len();
+
+        let root_bytes = read_range(&mut file, file_length, header.root_dir_offset, 0, header.root_dir_length)?;
+        let root = parse_directory(&decompress(root_bytes, header.internal_compression)?)?;
+
+        Ok(Self {
+            header,
+            file: Mutex::new(file),
+            file_length,
+            root,
+            leaves: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Reads `length` bytes at `offset` into the section starting at `section`
+    fn read(&self, section: u64, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        read_range(&mut self.file.lock().unwrap(), self.file_length, section, offset, length)
+    }
+
+    fn leaf_directory(&self, offset: u64, length: u64) -> io::Result<Vec<DirEntry>> {
+        if let Some(entries) = self.leaves.lock().unwrap().get(&offset) {
+            return Ok(entries.clone());
+        }
+        let bytes = self.read(self.header.leaf_dirs_offset, offset, length)?;
+        let entries = parse_directory(&decompress(bytes, self.header.internal_compression)?)?;
+        self.leaves.lock().unwrap().insert(offset, entries.clone());
+        Ok(entries)
+    }
+
+    pub fn get_tile(&self, x: u64, y: u64, zoom: u64) -> io::Result<Option<Vec<u8>>> {
+        let tile_id = zxy_to_tile_id(zoom as u8, x, y);
+        let mut directory = self.root.clone();
+
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let Some(entry) = find_tile(&directory, tile_id) else {
+                return Ok(None);
+            };
+            if entry.run_length > 0 {
+                let bytes = self.read(self.header.tile_data_offset, entry.offset, entry.length)?;
+                return decompress(bytes, self.header.tile_compression).map(Some);
+            }
+            // A run length of zero points at a leaf directory
+            directory = self.leaf_directory(entry.offset, entry.length)?;
+        }
+        Ok(None)
+    }
+}
+
+impl TileSource for PmTilesSource {
+    fn fetch(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, TileError> {
+        match self.get_tile(x, y, zoom) {
+            Ok(tile) => Ok(tile.unwrap_or_default()),
+            // The archive is damaged, rather than unreadable
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(TileError::Decode(e.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn metadata(&self) -> TileSourceMetadata {
+        let header = &self.header;
+        TileSourceMetadata {
+            min_zoom: header.min_zoom as u32,
+            max_zoom: header.max_zoom as u32,
+            bounds: Some([header.min_lon, header.min_lat, header.max_lon, header.max_lat]),
+            center: Some(LonLat::new(header.center_lon, header.center_lat)),
+            center_zoom: Some(header.center_zoom as u32),
+            attribution: None,
+        }
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads `length` bytes at `section + offset`, checking they are in the file first so a damaged or hostile archive can't ask for more than is there
+fn read_range(file: &mut File, file_length: u64, section: u64, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let start = section.checked_add(offset);
+    if start.and_then(|start| start.checked_add(length)).is_none_or(|end| end > file_length) {
+        return Err(invalid_data(format!("{} bytes at {} + {} reach past the end of the archive", length, section, offset)));
+    }
+    file.seek(SeekFrom::Start(section + offset))?;
+    let mut bytes = vec![0; length as usize];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn decompress(bytes: Vec<u8>, compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None | Compression::Unknown => Ok(bytes),
+        Compression::Gzip => read_decompressed(GzDecoder::new(bytes.as_slice())),
+        Compression::Brotli => decompress_brotli(&bytes),
+        other => Err(invalid_data(format!("{:?} compression is not supported", other))),
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*pos).ok_or_else(|| invalid_data("truncated directory"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(invalid_data("varint too long"));
+        }
+    }
+}
+
+/// Directories are stored column by column: tile ids (delta encoded), run lengths, lengths, then offsets
+fn parse_directory(bytes: &[u8]) -> io::Result<Vec<DirEntry>> {
+    let mut pos = 0;
+    let count = read_varint(bytes, &mut pos)?;
+    // Every entry takes at least four bytes, a bigger count is a damaged directory and not worth allocating for
+    if count > bytes.len() as u64 / 4 {
+        return Err(invalid_data(format!("directory of {} bytes can't hold {} entries", bytes.len(), count)));
+    }
+    let count = count as usize;
+    let mut entries = vec![DirEntry { tile_id: 0, offset: 0, length: 0, run_length: 0 }; count];
+
+    let mut last_id = 0_u64;
+    for entry in entries.iter_mut() {
+        last_id = last_id.checked_add(read_varint(bytes, &mut pos)?).ok_or_else(|| invalid_data("tile id out of range"))?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(bytes, &mut pos)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(bytes, &mut pos)?;
+    }
+    let mut previous_end = 0;
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let value = read_varint(bytes, &mut pos)?;
+        entry.offset = if value == 0 && i > 0 {
+            // Zero means the tile directly follows the previous one
+            previous_end
+        } else {
+            value.saturating_sub(1)
+        };
+        previous_end = entry.offset.saturating_add(entry.length);
+    }
+    Ok(entries)
+}
+
+fn find_tile(entries: &[DirEntry], tile_id: u64) -> Option<DirEntry> {
+    let index = match entries.binary_search_by_key(&tile_id, |entry| entry.tile_id) {
+        Ok(index) => return Some(entries[index]),
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let entry = entries[index];
+    if entry.run_length == 0 || tile_id - entry.tile_id < entry.run_length {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Tile ids count every tile of the lower zoom levels, then walk the current level along a Hilbert curve
+pub fn zxy_to_tile_id(zoom: u8, x: u64, y: u64) -> u64 {
+    let lower_levels = ((1u64 << (2 * zoom as u64)) - 1) / 3;
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = (1u64 << zoom) / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u64;
+        let ry = ((y & s) > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                // Only the bits below `s` matter from here on
+                x = (s - 1) - (x & (s - 1));
+                y = (s - 1) - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    lower_levels + d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made by `tests/fixtures/make_pmtiles.py`, its root directory only points at gzipped leaf directories
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/leaves.pmtiles");
+
+    fn entry(tile_id: u64, run_length: u64) -> DirEntry {
+        DirEntry { tile_id, offset: 0, length: 1, run_length }
+    }
+
+    /// The fixture with `edit` made to it, written to a file of its own
+    fn damaged(name: &str, edit: impl FnOnce(&mut Vec<u8>)) -> std::path::PathBuf {
+        let mut bytes = std::fs::read(FIXTURE).unwrap();
+        edit(&mut bytes);
+        let path = std::env::temp_dir().join(format!("bevy-ofm-viewer-{}-{}.pmtiles", name, std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_header() {
+        let bytes = std::fs::read(FIXTURE).unwrap();
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.root_dir_offset, HEADER_LENGTH as u64);
+        assert_eq!(header.internal_compression, Compression::Gzip);
+        assert_eq!(header.tile_compression, Compression::None);
+        assert_eq!((header.min_zoom, header.max_zoom, header.center_zoom), (0, 2, 1));
+        assert_eq!((header.min_lon, header.max_lat), (-180.0, 85.0));
+        assert!((header.center_lat - 52.2053).abs() < 1e-7);
+
+        assert!(Header::parse(&bytes[..HEADER_LENGTH - 1]).is_err());
+        let mut old_version = bytes.clone();
+        old_version[7] = 2;
+        assert!(Header::parse(&old_version).is_err());
+    }
+
+    #[test]
+    fn tile_ids_match_the_spec() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(12, 3423, 1763), 19_078_479);
+    }
+
+    #[test]
+    fn finds_tiles_in_runs() {
+        let entries = [entry(5, 4), entry(14, 1)];
+        assert!(find_tile(&entries, 4).is_none());
+        assert_eq!(find_tile(&entries, 5).unwrap().tile_id, 5);
+        assert_eq!(find_tile(&entries, 8).unwrap().tile_id, 5);
+        assert!(find_tile(&entries, 9).is_none());
+        assert_eq!(find_tile(&entries, 14).unwrap().tile_id, 14);
+        assert!(find_tile(&entries, 15).is_none());
+
+        // Leaf directories cover everything up to the next entry
+        let leaves = [entry(0, 0), entry(100, 0)];
+        assert_eq!(find_tile(&leaves, 99).unwrap().tile_id, 0);
+        assert_eq!(find_tile(&leaves, 1000).unwrap().tile_id, 100);
+    }
+
+    #[test]
+    fn gets_tiles_through_leaf_directories() {
+        let source = PmTilesSource::open(FIXTURE).unwrap();
+        let tile = |x, y, zoom| source.get_tile(x, y, zoom).unwrap().map(|data| String::from_utf8(data).unwrap());
+        assert_eq!(tile(0, 0, 0).as_deref(), Some("tile 0/0/0"));
+        assert_eq!(tile(1, 0, 1).as_deref(), Some("tile 1/1/0"));
+        // Ids 5 to 8 are one run
+        assert_eq!(tile(0, 0, 2).as_deref(), Some("the same for 5 to 8"));
+        assert_eq!(tile(1, 1, 2).as_deref(), Some("the same for 5 to 8"));
+        assert_eq!(tile(0, 1, 2).as_deref(), Some("the same for 5 to 8"));
+        assert_eq!(tile(0, 2, 2), None);
+        assert_eq!(tile(2, 3, 2).as_deref(), Some("tile 2/2/3"));
+        assert_eq!(tile(3, 3, 2), None);
+    }
+
+    #[test]
+    fn damaged_archives_are_errors() {
+        // A root directory longer than the whole file
+        let path = damaged("long-root", |bytes| bytes[16..24].copy_from_slice(&u64::MAX.to_le_bytes()));
+        assert_eq!(PmTilesSource::open(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        std::fs::remove_file(&path).unwrap();
+
+        // Cut off a byte into the tile data, the directories still point past it
+        let path = damaged("truncated", |bytes| bytes.truncate(253));
+        let source = PmTilesSource::open(&path).unwrap();
+        for (x, y, zoom) in [(0, 0, 0), (1, 0, 1), (0, 0, 2), (2, 3, 2)] {
+            assert!(matches!(source.fetch(x, y, zoom), Err(TileError::Decode(_))), "{}/{}/{}", zoom, x, y);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // Leaf directories that would start past the end of any file
+        let path = damaged("leaf-offset", |bytes| bytes[40..48].copy_from_slice(&u64::MAX.to_le_bytes()));
+        let source = PmTilesSource::open(&path).unwrap();
+        assert!(matches!(source.fetch(0, 0, 0), Err(TileError::Decode(_))));
+        std::fs::remove_file(&path).unwrap();
+
+        // A directory claiming far more entries than it has bytes for
+        assert!(parse_directory(&[0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+    }
+}
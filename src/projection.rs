@@ -0,0 +1,307 @@
+use std::f64::consts::PI;
+
+use bevy::math::{DVec2, Vec2};
+
+/// Half the circumference of the earth at the equator, where the web mercator plane ends in meters
+pub const MERCATOR_EXTENT: f64 = 20_037_508.342_789_244;
+/// The latitude at which the web mercator plane is square, nothing north or south of it is shown
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// A WGS84 position in degrees. Always longitude first, like GeoJSON and tile metadata.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LonLat {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+/// An EPSG:3857 web mercator position in meters, x goes east and y goes north
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mercator {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// A position in the tile grid of a zoom level, in tiles from the north west corner of the world.
+/// The whole part is the tile it is in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TilePoint {
+    pub x: f64,
+    pub y: f64,
+    pub zoom: u32,
+}
+
+/// A tile of the XYZ scheme, y counts down from the north
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tile {
+    pub x: i32,
+    pub y: i32,
+    pub zoom: u32,
+}
+
+/// Bevy's world space: `tile_size` units per tile of `zoom`, measured from `origin`, with y going up.
+/// Screen space is left to Bevy, see `Camera::viewport_to_world_2d`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldSpace {
+    pub origin: Mercator,
+    pub zoom: u32,
+    pub tile_size: f64,
+}
+
+/// How many tiles there are across a zoom level
+pub fn tiles_across(zoom: u32) -> f64 {
+    2_f64.powi(zoom as i32)
+}
+
+pub fn meters_per_tile(zoom: u32) -> f64 {
+    MERCATOR_EXTENT * 2.0 / tiles_across(zoom)
+}
+
+pub fn tile_width_degrees(zoom: u32) -> f64 {
+    360.0 / tiles_across(zoom)
+}
+
+impl LonLat {
+    pub const fn new(lon: f64, lat: f64) -> Self {
+        Self { lon, lat }
+    }
+
+    pub fn to_mercator(self) -> Mercator {
+        let lat = self.lat.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+        Mercator {
+            x: self.lon / 180.0 * MERCATOR_EXTENT,
+            y: lat.to_radians().tan().asinh() / PI * MERCATOR_EXTENT,
+        }
+    }
+
+    pub fn to_tile_point(self, zoom: u32) -> TilePoint {
+        self.to_mercator().to_tile_point(zoom)
+    }
+
+    /// The tile this position is in
+    pub fn to_tile(self, zoom: u32) -> Tile {
+        self.to_tile_point(zoom).tile()
+    }
+}
+
+impl From<LonLat> for geo::Coord<f64> {
+    fn from(lon_lat: LonLat) -> Self {
+        geo::coord! { x: lon_lat.lon, y: lon_lat.lat }
+    }
+}
+
+impl Mercator {
+    pub const fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
+    }
+
+    pub fn to_lon_lat(self) -> LonLat {
+        LonLat {
+            lon: self.x / MERCATOR_EXTENT * 180.0,
+            lat: (self.y / MERCATOR_EXTENT * PI).sinh().atan().to_degrees(),
+        }
+    }
+
+    pub fn to_tile_point(self, zoom: u32) -> TilePoint {
+        let n = tiles_across(zoom);
+        TilePoint {
+            x: (self.x / MERCATOR_EXTENT + 1.0) / 2.0 * n,
+            y: (1.0 - self.y / MERCATOR_EXTENT) / 2.0 * n,
+            zoom,
+        }
+    }
+
+    pub fn to_dvec2(self) -> DVec2 {
+        DVec2::new(self.x, self.y)
+    }
+}
+
+impl TilePoint {
+    pub fn to_mercator(self) -> Mercator {
+        let n = tiles_across(self.zoom);
+        Mercator {
+            x: (self.x / n * 2.0 - 1.0) * MERCATOR_EXTENT,
+            y: (1.0 - self.y / n * 2.0) * MERCATOR_EXTENT,
+        }
+    }
+
+    pub fn to_lon_lat(self) -> LonLat {
+        self.to_mercator().to_lon_lat()
+    }
+
+    pub fn tile(self) -> Tile {
+        Tile::new(self.x.floor() as i32, self.y.floor() as i32, self.zoom)
+    }
+}
+
+impl Tile {
+    pub const fn new(x: i32, y: i32, zoom: u32) -> Self {
+        Self { x, y, zoom }
+    }
+
+    /// The north west corner
+    pub fn corner(self) -> TilePoint {
+        TilePoint {
+            x: self.x as f64,
+            y: self.y as f64,
+            zoom: self.zoom,
+        }
+    }
+
+    /// The middle of the tile
+    pub fn center(self) -> TilePoint {
+        TilePoint {
+            x: self.x as f64 + 0.5,
+            y: self.y as f64 + 0.5,
+            zoom: self.zoom,
+        }
+    }
+
+    pub fn to_lon_lat(self) -> LonLat {
+        self.corner().to_lon_lat()
+    }
+
+    /// The same tile with x wrapped around the antimeridian, `None` past the poles where there are no tiles
+    pub fn wrapped(self) -> Option<Tile> {
+        let n = 1_i32 << self.zoom;
+        if !(0..n).contains(&self.y) {
+            return None;
+        }
+        Some(Tile::new(self.x.rem_euclid(n), self.y, self.zoom))
+    }
+}
+
+impl WorldSpace {
+    pub fn new(origin: LonLat, zoom: u32, tile_size: f64) -> Self {
+        Self {
+            origin: origin.to_mercator(),
+            zoom,
+            tile_size,
+        }
+    }
+
+    fn meters_per_unit(&self) -> f64 {
+        meters_per_tile(self.zoom) / self.tile_size
+    }
+
+    /// Only the offset from the origin is brought down to `f32`, so it stays precise near the origin
+    pub fn to_world(&self, mercator: Mercator) -> Vec2 {
+        ((mercator.to_dvec2() - self.origin.to_dvec2()) / self.meters_per_unit()).as_vec2()
+    }
+
+    pub fn to_mercator(&self, world: Vec2) -> Mercator {
+        let position = self.origin.to_dvec2() + world.as_dvec2() * self.meters_per_unit();
+        Mercator::new(position.x, position.y)
+    }
+
+    pub fn lon_lat_to_world(&self, lon_lat: LonLat) -> Vec2 {
+        self.to_world(lon_lat.to_mercator())
+    }
+
+    pub fn world_to_lon_lat(&self, world: Vec2) -> LonLat {
+        self.to_mercator(world).to_lon_lat()
+    }
+
+    pub fn world_to_tile_point(&self, world: Vec2) -> TilePoint {
+        self.to_mercator(world).to_tile_point(self.zoom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fixed xorshift sequence, so the sweeps cover the same points on every run
+    struct Sweep(u64);
+
+    impl Sweep {
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 11) as f64 / (1_u64 << 53) as f64
+        }
+
+        fn lon_lat(&mut self) -> LonLat {
+            LonLat::new(self.next() * 360.0 - 180.0, (self.next() * 2.0 - 1.0) * MAX_LATITUDE)
+        }
+    }
+
+    fn assert_close(a: LonLat, b: LonLat, tolerance: f64) {
+        assert!(
+            (a.lon - b.lon).abs() <= tolerance && (a.lat - b.lat).abs() <= tolerance,
+            "{:?} and {:?} are further apart than {}",
+            a,
+            b,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn tile_point_round_trip() {
+        let mut sweep = Sweep(0x5eed_0f71);
+        for zoom in 0..=20 {
+            for _ in 0..1000 {
+                let lon_lat = sweep.lon_lat();
+                assert_close(lon_lat.to_tile_point(zoom).to_lon_lat(), lon_lat, 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn world_round_trip() {
+        let mut sweep = Sweep(0x0123_4567_89ab);
+        for zoom in 0..=20 {
+            for _ in 0..1000 {
+                let lon_lat = sweep.lon_lat();
+                // The camera stays within a few tiles of the origin, it is moved along once it gets further
+                let mut origin = lon_lat.to_tile_point(zoom);
+                origin.x += sweep.next() * 8.0 - 4.0;
+                origin.y += sweep.next() * 8.0 - 4.0;
+                let world = WorldSpace {
+                    origin: origin.to_mercator(),
+                    zoom,
+                    tile_size: 512.0,
+                };
+                let round_trip = world.world_to_lon_lat(world.lon_lat_to_world(lon_lat));
+                // That close to the origin f32 world positions are good to a millionth of a tile
+                assert_close(round_trip, lon_lat, tile_width_degrees(zoom) * 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn mercator_reference_points() {
+        let origin = LonLat::new(0.0, 0.0).to_mercator();
+        assert!(origin.x.abs() < 1e-9 && origin.y.abs() < 1e-9);
+        assert_eq!(LonLat::new(180.0, 0.0).to_mercator().x, MERCATOR_EXTENT);
+        assert_eq!(LonLat::new(-180.0, 0.0).to_mercator().x, -MERCATOR_EXTENT);
+        assert!((LonLat::new(0.0, MAX_LATITUDE).to_mercator().y - MERCATOR_EXTENT).abs() < 1e-6);
+        assert!((LonLat::new(0.0, -MAX_LATITUDE).to_mercator().y + MERCATOR_EXTENT).abs() < 1e-6);
+        // Further north is squashed onto the edge
+        assert!((LonLat::new(0.0, 89.0).to_mercator().y - MERCATOR_EXTENT).abs() < 1e-6);
+        // The Eiffel Tower and the Statue of Liberty in EPSG:3857 metres
+        for (lon_lat, x, y) in [
+            (LonLat::new(2.2945, 48.8584), 255_422.571_625, 6_250_868.901_480),
+            (LonLat::new(-74.0445, 40.6892), -8_242_596.036_043, 4_966_606.257_308),
+        ] {
+            let mercator = lon_lat.to_mercator();
+            assert!((mercator.x - x).abs() < 1e-3 && (mercator.y - y).abs() < 1e-3, "{:?} {} {}", lon_lat, mercator.x, mercator.y);
+        }
+    }
+
+    #[test]
+    fn cambridge_tile() {
+        assert_eq!(LonLat::new(0.1218, 52.2053).to_tile(14), Tile::new(8197, 5396, 14));
+    }
+
+    #[test]
+    fn wrapped_tiles() {
+        assert_eq!(Tile::new(-1, 5, 3).wrapped(), Some(Tile::new(7, 5, 3)));
+        assert_eq!(Tile::new(-17, 5, 3).wrapped(), Some(Tile::new(7, 5, 3)));
+        assert_eq!(Tile::new(8, 5, 3).wrapped(), Some(Tile::new(0, 5, 3)));
+        assert_eq!(Tile::new(17, 0, 3).wrapped(), Some(Tile::new(1, 0, 3)));
+        assert_eq!(Tile::new(3, 7, 3).wrapped(), Some(Tile::new(3, 7, 3)));
+        assert_eq!(Tile::new(3, -1, 3).wrapped(), None);
+        assert_eq!(Tile::new(3, 8, 3).wrapped(), None);
+    }
+}
@@ -0,0 +1,386 @@
+use std::{collections::HashMap, error::Error, fs, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+
+use bevy::prelude::*;
+use raqote::SolidSource;
+use serde::Deserialize;
+
+use crate::{http::HttpClient, maplibre_style::{import_maplibre_style, is_maplibre_style}};
+
+pub const DEFAULT_STYLE: &str = include_str!("../assets/styles/default.json");
+
+/// An ordered list of layers, drawn first to last
+#[derive(Debug, Clone, Deserialize)]
+pub struct Style {
+    /// Colour the tile is cleared to before any layer is drawn
+    #[serde(default)]
+    pub background: Option<StyleColor>,
+    pub layers: Vec<StyleLayer>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StyleLayer {
+    pub id: String,
+    /// The vector tile layer the features come from
+    pub source_layer: String,
+    #[serde(default)]
+    pub filter: Filter,
+    #[serde(default)]
+    pub min_zoom: Option<u32>,
+    #[serde(default)]
+    pub max_zoom: Option<u32>,
+    /// Numeric feature property to order the features by, lowest drawn first
+    #[serde(default)]
+    pub sort_key: Option<String>,
+    pub paint: Paint,
+}
+
+impl StyleLayer {
+    pub fn visible_at(&self, zoom: u32) -> bool {
+        self.min_zoom.is_none_or(|min| zoom >= min) && self.max_zoom.is_none_or(|max| zoom <= max)
+    }
+}
+
+fn one() -> ZoomValue {
+    ZoomValue::Constant(1.0)
+}
+
+/// Either a plain number or `{"base": 1.2, "stops": [[5, 1.0], [14, 4.0]]}`,
+/// which is interpolated between the stops by zoom level
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ZoomValue {
+    Constant(f32),
+    Stops {
+        #[serde(default = "base")]
+        base: f32,
+        stops: Vec<(f32, f32)>,
+    },
+}
+
+fn base() -> f32 {
+    1.0
+}
+
+impl ZoomValue {
+    pub fn at(&self, zoom: f32) -> f32 {
+        let (base, stops) = match self {
+            ZoomValue::Constant(value) => return *value,
+            ZoomValue::Stops { base, stops } => (*base, stops),
+        };
+        let (Some(first), Some(last)) = (stops.first(), stops.last()) else {
+            return 0.0;
+        };
+        if zoom <= first.0 {
+            return first.1;
+        }
+        if zoom >= last.0 {
+            return last.1;
+        }
+
+        let i = stops.windows(2).position(|pair| zoom < pair[1].0).unwrap_or(0);
+        let ((z0, v0), (z1, v1)) = (stops[i], stops[i + 1]);
+        let t = if (base - 1.0).abs() < f32::EPSILON {
+            (zoom - z0) / (z1 - z0)
+        } else {
+            (base.powf(zoom - z0) - 1.0) / (base.powf(z1 - z0) - 1.0)
+        };
+        v0 + (v1 - v0) * t
+    }
+}
+
+/// Widths, radii and dashes are in pixels of the rendered tile
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Paint {
+    Fill {
+        color: StyleColor,
+        #[serde(default = "one")]
+        opacity: ZoomValue,
+    },
+    Line {
+        color: StyleColor,
+        #[serde(default = "one")]
+        width: ZoomValue,
+        #[serde(default = "one")]
+        opacity: ZoomValue,
+        #[serde(default)]
+        dash: Vec<f32>,
+    },
+    Circle {
+        color: StyleColor,
+        #[serde(default = "one")]
+        radius: ZoomValue,
+        #[serde(default = "one")]
+        opacity: ZoomValue,
+    },
+    /// A text label, drawn at points and along lines
+    Symbol {
+        /// Properties to take the text from, the first one a feature has is used
+        text: Vec<String>,
+        color: StyleColor,
+        #[serde(default)]
+        halo: Option<StyleColor>,
+        #[serde(default = "text_size")]
+        size: ZoomValue,
+    },
+}
+
+fn text_size() -> ZoomValue {
+    ZoomValue::Constant(12.0)
+}
+
+/// Written as `#rrggbb` or `#rrggbbaa`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct StyleColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl TryFrom<String> for StyleColor {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.strip_prefix('#').ok_or_else(|| format!("colour {} should start with #", value))?;
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .ok_or_else(|| format!("invalid colour {}", value))
+        };
+        match hex.len() {
+            6 => Ok(StyleColor { r: channel(0)?, g: channel(2)?, b: channel(4)?, a: 0xff }),
+            8 => Ok(StyleColor { r: channel(0)?, g: channel(2)?, b: channel(4)?, a: channel(6)? }),
+            _ => Err(format!("invalid colour {}", value)),
+        }
+    }
+}
+
+impl StyleColor {
+    pub fn to_source(self) -> SolidSource {
+        SolidSource::from_unpremultiplied_argb(self.a, self.r, self.g, self.b)
+    }
+}
+
+/// A property value of a feature, or one to compare it against
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// A string from a tile that also reads as a number, like a road ref "12",
+    /// so it can be compared as either
+    #[serde(skip)]
+    Numeric(String, f64),
+    Null,
+}
+
+// mvt-reader hands out every property value as a string, so the type is guessed back from it
+impl From<&String> for PropertyValue {
+    fn from(value: &String) -> Self {
+        match value.as_str() {
+            "true" => PropertyValue::Bool(true),
+            "false" => PropertyValue::Bool(false),
+            text => match text.parse::<f64>() {
+                Ok(number) if number.is_finite() => PropertyValue::Numeric(value.clone(), number),
+                _ => PropertyValue::String(value.clone()),
+            },
+        }
+    }
+}
+
+impl PropertyValue {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            PropertyValue::Number(number) | PropertyValue::Numeric(_, number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The text a label shows for this value, if any
+    pub fn to_label_text(&self) -> Option<String> {
+        let text = match self {
+            PropertyValue::String(text) | PropertyValue::Numeric(text, _) => text.clone(),
+            PropertyValue::Number(number) => number.to_string(),
+            PropertyValue::Bool(value) => value.to_string(),
+            PropertyValue::Null => return None,
+        };
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// Whether a feature's value matches a filter's, with numeric strings matching
+    /// both the text they were read from and the number
+    pub fn matches(&self, other: &PropertyValue) -> bool {
+        match (self, other) {
+            (PropertyValue::Numeric(text, number), other) | (other, PropertyValue::Numeric(text, number)) => match other {
+                PropertyValue::String(other) => text == other,
+                PropertyValue::Number(other) => number == other,
+                PropertyValue::Numeric(other_text, other) => text == other_text || number == other,
+                _ => false,
+            },
+            _ => self == other,
+        }
+    }
+}
+
+pub type Properties = HashMap<String, PropertyValue>;
+
+/// Which features of the source layer a style layer applies to, for example
+/// `{"all": [{"eq": ["class", "river"]}, {"has": "name"}]}`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    #[default]
+    Always,
+    All(Vec<Filter>),
+    Any(Vec<Filter>),
+    Not(Box<Filter>),
+    Eq(String, PropertyValue),
+    NotEq(String, PropertyValue),
+    In(String, Vec<PropertyValue>),
+    Has(String),
+}
+
+impl Filter {
+    pub fn matches(&self, properties: &Properties) -> bool {
+        match self {
+            Filter::Always => true,
+            Filter::All(filters) => filters.iter().all(|filter| filter.matches(properties)),
+            Filter::Any(filters) => filters.iter().any(|filter| filter.matches(properties)),
+            Filter::Not(filter) => !filter.matches(properties),
+            Filter::Eq(key, value) => properties.get(key).is_some_and(|found| found.matches(value)),
+            Filter::NotEq(key, value) => !properties.get(key).is_some_and(|found| found.matches(value)),
+            Filter::In(key, values) => properties.get(key).is_some_and(|found| values.iter().any(|value| found.matches(value))),
+            Filter::Has(key) => properties.contains_key(key),
+        }
+    }
+}
+
+impl Style {
+    /// Loads one of our styles or a MapLibre style, from a file or an http(s) URL
+    pub fn load(location: &str) -> Result<Self, Box<dyn Error>> {
+        let text = if location.starts_with("http://") || location.starts_with("https://") {
+            String::from_utf8(HttpClient::global().get(location, &[])?.body)?
+        } else {
+            fs::read_to_string(location)?
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&text)?;
+        if is_maplibre_style(&json) {
+            let (style, warnings) = import_maplibre_style(&json);
+            for warning in warnings {
+                warn!("Style {}: {}", location, warning);
+            }
+            Ok(style)
+        } else {
+            Ok(serde_json::from_value(json)?)
+        }
+    }
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        serde_json::from_str(DEFAULT_STYLE).expect("The built in style should be valid")
+    }
+}
+
+// Every style gets its own version, so tiles drawn with another one are never mixed in
+static NEXT_STYLE_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// The style used to rasterize tiles, picked when the `App` is built
+#[derive(Resource, Clone, Deref)]
+pub struct ActiveStyle {
+    #[deref]
+    pub style: Arc<Style>,
+    pub version: u64,
+}
+
+impl ActiveStyle {
+    pub fn new(style: Style) -> Self {
+        Self {
+            style: Arc::new(style),
+            version: NEXT_STYLE_VERSION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ActiveStyle {
+    fn default() -> Self {
+        Self::new(Style::default())
+    }
+}
+
+/// Loads the style given with `--style <file or URL>`, or the built in one
+pub fn style_from_args(args: impl IntoIterator<Item = String>) -> ActiveStyle {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--style" {
+            if let Some(path) = args.next() {
+                match Style::load(&path) {
+                    Ok(style) => return ActiveStyle::new(style),
+                    Err(e) => error!("Failed to load style {}: {}", path, e),
+                }
+            }
+        }
+    }
+    ActiveStyle::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(key: &str, value: &str) -> Properties {
+        HashMap::from([(key.to_string(), PropertyValue::from(&value.to_string()))])
+    }
+
+    #[test]
+    fn tile_values_get_their_type_guessed() {
+        assert_eq!(PropertyValue::from(&"true".to_string()), PropertyValue::Bool(true));
+        assert_eq!(PropertyValue::from(&"A1".to_string()), PropertyValue::String("A1".to_string()));
+        assert_eq!(PropertyValue::from(&"012".to_string()), PropertyValue::Numeric("012".to_string(), 12.0));
+        assert_eq!(PropertyValue::from(&"inf".to_string()), PropertyValue::String("inf".to_string()));
+        assert_eq!(PropertyValue::from(&"1984".to_string()).as_number(), Some(1984.0));
+    }
+
+    #[test]
+    fn label_text_of_every_kind_of_value() {
+        let cases = [
+            (PropertyValue::String("Main Street".to_string()), Some("Main Street")),
+            (PropertyValue::String(String::new()), None),
+            (PropertyValue::from(&"A1".to_string()), Some("A1")),
+            (PropertyValue::from(&"012".to_string()), Some("012")),
+            (PropertyValue::Number(2962.0), Some("2962")),
+            (PropertyValue::Number(12.5), Some("12.5")),
+            (PropertyValue::Bool(true), Some("true")),
+            (PropertyValue::Null, None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(value.to_label_text().as_deref(), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn numeric_strings_match_as_text_or_number() {
+        let string = |text: &str| PropertyValue::String(text.to_string());
+        let cases = [
+            ("12", Filter::Eq("ref".to_string(), string("12")), true),
+            ("12", Filter::Eq("ref".to_string(), PropertyValue::Number(12.0)), true),
+            ("012", Filter::Eq("ref".to_string(), string("012")), true),
+            ("012", Filter::Eq("ref".to_string(), PropertyValue::Number(12.0)), true),
+            ("012", Filter::Eq("ref".to_string(), string("12")), false),
+            ("1984", Filter::NotEq("ref".to_string(), string("1984")), false),
+            ("1984", Filter::NotEq("ref".to_string(), PropertyValue::Number(1985.0)), true),
+            ("A1", Filter::Eq("ref".to_string(), string("A1")), true),
+            ("A1", Filter::In("ref".to_string(), vec![string("A2"), string("A1")]), true),
+            ("3", Filter::In("ref".to_string(), vec![PropertyValue::Number(3.0), string("4")]), true),
+            ("4", Filter::In("ref".to_string(), vec![PropertyValue::Number(3.0), string("4")]), true),
+            ("5", Filter::In("ref".to_string(), vec![PropertyValue::Number(3.0), string("4")]), false),
+        ];
+        for (value, filter, expected) in cases {
+            assert_eq!(filter.matches(&properties("ref", value)), expected, "{:?} on {}", filter, value);
+        }
+    }
+}
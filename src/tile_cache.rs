@@ -0,0 +1,491 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fs, io::{self, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::http::HttpResponse;
+
+// How much disk the cache may use unless set with `--cache-size`
+pub const DEFAULT_CACHE_BYTES: u64 = 512 * 1024 * 1024;
+// How long a tile is used without asking the server again, when the server doesn't say
+const DEFAULT_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+// A temporary file this old is left from an interrupted write, younger ones may still be written by another run
+const STALE_TEMP_SECS: u64 = 10 * 60;
+
+type TileKey = (u64, u64, u64);
+
+// Keeps temporary file names apart when several workers write at once
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// What the server said about a tile, kept next to its bytes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheMeta {
+    pub etag: Option<String>,
+    /// Unix seconds after which the tile has to be revalidated
+    pub expires: u64,
+}
+
+impl CacheMeta {
+    /// For a tile the server said nothing about, kept for the default time
+    pub fn fresh() -> Self {
+        Self {
+            etag: None,
+            expires: unix_now().saturating_add(DEFAULT_MAX_AGE_SECS),
+        }
+    }
+
+    /// Reads `Cache-Control` and `ETag` off a response, `None` if the server asked for it not to be stored
+    pub fn from_response(response: &HttpResponse) -> Option<Self> {
+        let mut max_age = DEFAULT_MAX_AGE_SECS;
+        if let Some(cache_control) = response.header("Cache-Control") {
+            for directive in cache_control.split(',').map(|directive| directive.trim().to_ascii_lowercase()) {
+                if directive == "no-store" {
+                    return None;
+                } else if directive == "no-cache" {
+                    max_age = 0;
+                } else if let Some(seconds) = directive.strip_prefix("max-age=") {
+                    max_age = seconds.trim_matches('"').parse().unwrap_or(max_age);
+                }
+            }
+        }
+        Some(Self {
+            etag: response.header("ETag").map(str::to_string),
+            expires: unix_now().saturating_add(max_age),
+        })
+    }
+}
+
+pub struct CachedTile {
+    pub data: Vec<u8>,
+    pub meta: CacheMeta,
+}
+
+impl CachedTile {
+    pub fn is_fresh(&self) -> bool {
+        unix_now() < self.meta.expires
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+/// Which tiles are on disk, and in what order they were last used
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<TileKey, CacheEntry>,
+    by_use: BTreeMap<u64, TileKey>,
+    total: u64,
+    clock: u64,
+    /// Tiles other sources and older tileset versions left under the same root, oldest first
+    others: VecDeque<(PathBuf, u64)>,
+    others_total: u64,
+}
+
+impl CacheIndex {
+    fn touch(&mut self, key: TileKey, size: u64) {
+        self.clock += 1;
+        if let Some(old) = self.entries.insert(key, CacheEntry { size, last_used: self.clock }) {
+            self.by_use.remove(&old.last_used);
+            self.total -= old.size;
+        }
+        self.by_use.insert(self.clock, key);
+        self.total += size;
+    }
+
+    fn remove(&mut self, key: TileKey) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.by_use.remove(&old.last_used);
+            self.total -= old.size;
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<TileKey> {
+        self.by_use.values().next().copied()
+    }
+}
+
+/// Downloaded tiles on disk, one directory per tile source and version.
+/// Every tile is a single file of a JSON header line and the tile bytes, written to a temporary file
+/// and renamed into place so a crash never leaves half a tile behind.
+/// `max_bytes` covers everything under the root. Once that is used up, tiles of other namespaces
+/// go first, then the least recently used tiles of this one.
+pub struct TileCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+impl TileCache {
+    /// Opens the cache for `namespace` under `root`, picking up whatever an earlier run left there
+    pub fn open(root: impl AsRef<Path>, namespace: &str, max_bytes: u64) -> io::Result<Self> {
+        let root = root.as_ref();
+        let dir = root.join(namespace);
+        fs::create_dir_all(&dir)?;
+
+        // Files are indexed oldest first, so their modification times carry the LRU order over from the last run
+        let mut found = Vec::new();
+        scan_cache_dir(&dir, &mut found, true)?;
+        found.sort_by_key(|(_, _, modified)| *modified);
+        let mut index = CacheIndex::default();
+        for (key, size, _) in found {
+            index.touch(key, size);
+        }
+
+        let mut others = Vec::new();
+        for entry in fs::read_dir(root)? {
+            let other_dir = entry?.path();
+            // Saved TileJSONs are tiny and needed to start offline
+            if other_dir == dir || !other_dir.is_dir() || other_dir.file_name().is_some_and(|name| name == "tilejson") {
+                continue;
+            }
+            let mut found = Vec::new();
+            if let Err(e) = scan_cache_dir(&other_dir, &mut found, false) {
+                warn!("Failed to scan cached tiles in {}: {}", other_dir.display(), e);
+            }
+            others.extend(found.into_iter().map(|((zoom, x, y), size, modified)| (tile_path(&other_dir, x, y, zoom), size, modified)));
+        }
+        others.sort_by_key(|(_, _, modified)| *modified);
+        index.others_total = others.iter().map(|(_, size, _)| size).sum();
+        index.others = others.into_iter().map(|(path, size, _)| (path, size)).collect();
+
+        let cache = Self {
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    fn tile_path(&self, x: u64, y: u64, zoom: u64) -> PathBuf {
+        tile_path(&self.dir, x, y, zoom)
+    }
+
+    pub fn get(&self, x: u64, y: u64, zoom: u64) -> Option<CachedTile> {
+        let path = self.tile_path(x, y, zoom);
+        let file = fs::read(&path).ok()?;
+        let Some(tile) = parse_tile_file(file) else {
+            warn!("Dropping unreadable cached tile {}/{}/{}", zoom, x, y);
+            let _ = fs::remove_file(&path);
+            self.index.lock().unwrap().remove((zoom, x, y));
+            return None;
+        };
+
+        let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+        self.index.lock().unwrap().touch((zoom, x, y), size);
+        // Keeps the LRU order for the next run, not worth failing the read over
+        let _ = fs::File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+        Some(tile)
+    }
+
+    pub fn put(&self, x: u64, y: u64, zoom: u64, data: &[u8], meta: &CacheMeta) -> io::Result<()> {
+        let path = self.tile_path(x, y, zoom);
+        let parent = path.parent().expect("tile paths are inside the cache directory");
+        fs::create_dir_all(parent)?;
+
+        let mut file = serde_json::to_vec(meta).map_err(io::Error::other)?;
+        file.push(b'\n');
+        file.extend_from_slice(data);
+        write_atomically(&path, &file)?;
+
+        let size = file.len() as u64;
+        self.index.lock().unwrap().touch((zoom, x, y), size);
+        self.evict();
+        Ok(())
+    }
+
+    /// Deletes least recently used tiles until the cache fits in its budget
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        while index.total + index.others_total > self.max_bytes {
+            if let Some((path, size)) = index.others.pop_front() {
+                index.others_total -= size;
+                remove_tile_file(&path);
+                continue;
+            }
+            let Some((zoom, x, y)) = index.least_recently_used() else {
+                break;
+            };
+            if let Err(e) = fs::remove_file(self.tile_path(x, y, zoom)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to evict cached tile {}/{}/{}: {}", zoom, x, y, e);
+                }
+            }
+            index.remove((zoom, x, y));
+        }
+    }
+}
+
+fn tile_path(dir: &Path, x: u64, y: u64, zoom: u64) -> PathBuf {
+    dir.join(zoom.to_string()).join(x.to_string()).join(format!("{}.tile", y))
+}
+
+/// Deletes a tile of another namespace, along with the directories it leaves empty
+fn remove_tile_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to evict cached tile {}: {}", path.display(), e);
+        }
+    }
+    // Only empty directories can be removed, so this stops at the first one still in use
+    for dir in path.ancestors().skip(1).take(3) {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so readers see the old file or the whole new one
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(
+        "{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = fs::File::create(&temp_path)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+fn parse_tile_file(mut file: Vec<u8>) -> Option<CachedTile> {
+    let header_end = file.iter().position(|&byte| byte == b'\n')?;
+    let meta = serde_json::from_slice(&file[..header_end]).ok()?;
+    let data = file.split_off(header_end + 1);
+    Some(CachedTile { data, meta })
+}
+
+/// Finds every `{z}/{x}/{y}.tile` under `dir`, and with `clean_up` deletes the temporary files an interrupted write left behind
+fn scan_cache_dir(dir: &Path, found: &mut Vec<(TileKey, u64, SystemTime)>, clean_up: bool) -> io::Result<()> {
+    let stale = SystemTime::now() - Duration::from_secs(STALE_TEMP_SECS);
+    for zoom_entry in fs::read_dir(dir)? {
+        let zoom_entry = zoom_entry?;
+        let Some(zoom) = parse_file_name(&zoom_entry.path(), None) else {
+            continue;
+        };
+        for x_entry in fs::read_dir(zoom_entry.path())? {
+            let x_entry = x_entry?;
+            let Some(x) = parse_file_name(&x_entry.path(), None) else {
+                continue;
+            };
+            for y_entry in fs::read_dir(x_entry.path())? {
+                let path = y_entry?.path();
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+                    if clean_up && modified.is_ok_and(|modified| modified < stale) {
+                        let _ = fs::remove_file(&path);
+                    }
+                    continue;
+                }
+                let Some(y) = parse_file_name(&path, Some("tile")) else {
+                    continue;
+                };
+                let metadata = fs::metadata(&path)?;
+                found.push(((zoom, x, y), metadata.len(), metadata.modified().unwrap_or(UNIX_EPOCH)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_file_name(path: &Path, extension: Option<&str>) -> Option<u64> {
+    if path.extension().and_then(|extension| extension.to_str()) != extension {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Turns a URL template into a directory name, so every source and tileset version gets its own cache
+pub fn cache_namespace(template: &str) -> String {
+    let template = template.split_once("://").map_or(template, |(_, rest)| rest);
+    let prefix = template.split('{').next().unwrap_or_default();
+    let namespace: String = prefix
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    let namespace = namespace.trim_matches(['_', '.']);
+    if namespace.is_empty() {
+        // Templates like `https://{s}.tile.example/{z}/{x}/{y}.png` have nothing in front to go by
+        return format!("template-{:016x}", fnv1a(template.as_bytes()));
+    }
+    namespace.to_string()
+}
+
+/// A hash that stays the same across runs and Rust versions, for names on disk
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The platform's cache directory, e.g. `~/.cache` on Linux, or `cache` next to the binary if there isn't one
+pub fn default_cache_root() -> PathBuf {
+    dirs::cache_dir().map_or_else(|| PathBuf::from("cache"), |dir| dir.join("bevy-ofm-viewer"))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Where downloaded tiles are kept, set with `--cache-dir <path>`, `--cache-size <megabytes>` and `--no-cache`.
+/// The size is for the whole directory, shared by every source and tileset version cached in it.
+#[derive(Debug, Clone)]
+pub struct CacheSettings {
+    pub root: Option<PathBuf>,
+    pub max_bytes: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            root: Some(default_cache_root()),
+            max_bytes: DEFAULT_CACHE_BYTES,
+        }
+    }
+}
+
+impl CacheSettings {
+    /// Opens the cache for a URL template, tiles are just downloaded every time if that fails
+    pub fn open(&self, template: &str) -> Option<TileCache> {
+        let root = self.root.as_ref()?;
+        match TileCache::open(root, &cache_namespace(template), self.max_bytes) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("Failed to open the tile cache in {}: {}", root.display(), e);
+                None
+            }
+        }
+    }
+}
+
+pub fn cache_settings_from_args(args: impl IntoIterator<Item = String>) -> CacheSettings {
+    let mut settings = CacheSettings::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cache-dir" => {
+                if let Some(dir) = args.next() {
+                    settings.root = Some(PathBuf::from(dir));
+                }
+            }
+            "--cache-size" => match args.next().and_then(|megabytes| megabytes.parse::<u64>().ok()) {
+                Some(megabytes) => settings.max_bytes = megabytes * 1024 * 1024,
+                None => warn!("--cache-size expects a number of megabytes"),
+            },
+            "--no-cache" => settings.root = None,
+            _ => {}
+        }
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own under the system's temporary directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bevy-ofm-viewer-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn namespaces_from_templates() {
+        let cases = [
+            ("https://tiles.openfreemap.org/planet/20250122_001001_pt/{z}/{x}/{y}.pbf", "tiles.openfreemap.org_planet_20250122_001001_pt"),
+            ("http://localhost:8080/tiles/{z}/{x}/{y}.mvt", "localhost_8080_tiles"),
+            ("tiles.example/{z}/{x}/{y}", "tiles.example"),
+        ];
+        for (template, namespace) in cases {
+            assert_eq!(cache_namespace(template), namespace, "{}", template);
+        }
+    }
+
+    #[test]
+    fn templates_starting_with_a_placeholder_get_a_hash() {
+        let a = cache_namespace("https://{s}.tile.example/{z}/{x}/{y}.png");
+        let b = cache_namespace("https://{s}.tile.other.example/{z}/{x}/{y}.png");
+        assert!(a.starts_with("template-") && b.starts_with("template-"), "{} {}", a, b);
+        assert_ne!(a, b);
+        assert_eq!(a, cache_namespace("https://{s}.tile.example/{z}/{x}/{y}.png"));
+    }
+
+    #[test]
+    fn tile_files_round_trip() {
+        let meta = CacheMeta {
+            etag: Some("\"abc\"".to_string()),
+            expires: 1234,
+        };
+        // Tile bytes can hold newlines of their own
+        let data = b"\x1a\n\x03one\ntwo".to_vec();
+        let mut file = serde_json::to_vec(&meta).unwrap();
+        file.push(b'\n');
+        file.extend_from_slice(&data);
+
+        let tile = parse_tile_file(file).unwrap();
+        assert_eq!(tile.data, data);
+        assert_eq!((tile.meta.etag.as_deref(), tile.meta.expires), (Some("\"abc\""), 1234));
+        assert!(parse_tile_file(b"no header".to_vec()).is_none());
+        assert!(parse_tile_file(b"{not json\ntile".to_vec()).is_none());
+    }
+
+    #[test]
+    fn writes_replace_the_whole_file() {
+        let dir = temp_dir("write");
+        let path = dir.join("0.tile");
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["0.tile"], "temporary files were left behind");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let dir = temp_dir("evict");
+        let meta = CacheMeta::default();
+        let tile_bytes = serde_json::to_vec(&meta).unwrap().len() as u64 + 1 + 4;
+        let cache = TileCache::open(&dir, "source", 3 * tile_bytes).unwrap();
+        for x in 0..3 {
+            cache.put(x, 0, 1, b"tile", &meta).unwrap();
+        }
+        // Using the oldest makes the second the one to go
+        assert!(cache.get(0, 0, 1).is_some());
+        cache.put(3, 0, 1, b"tile", &meta).unwrap();
+
+        let cached: Vec<bool> = (0..4).map(|x| cache.get(x, 0, 1).is_some()).collect();
+        assert_eq!(cached, [true, false, true, true]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_stale_temporary_files_are_cleaned_up() {
+        let dir = temp_dir("temp");
+        let old = SystemTime::now() - Duration::from_secs(STALE_TEMP_SECS + 60);
+        let mut temp_files = Vec::new();
+        for namespace in ["source", "other"] {
+            let tile_dir = dir.join(namespace).join("1").join("0");
+            fs::create_dir_all(&tile_dir).unwrap();
+            for (name, modified) in [("0.tile.1.0.tmp", old), ("1.tile.1.1.tmp", SystemTime::now())] {
+                let path = tile_dir.join(name);
+                fs::File::create(&path).unwrap().set_modified(modified).unwrap();
+                temp_files.push(path);
+            }
+        }
+
+        TileCache::open(&dir, "source", u64::MAX).unwrap();
+        // Another run may still be writing the recent one, and other namespaces are left to their own runs
+        let left: Vec<bool> = temp_files.iter().map(|path| path.exists()).collect();
+        assert_eq!(left, [false, true, true, true]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,46 +1,90 @@
-use std::thread;
-
 // Thank you for the example: https://github.com/StarArawn/bevy_ecs_tilemap/blob/main/examples/chunking.rs
-use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use bevy::{ecs::{schedule::SystemConfigs, system::SystemParam}, prelude::*, utils::{HashMap, HashSet}, window::PrimaryWindow};
 use bevy_ecs_tilemap::{map::{TilemapGridSize, TilemapId, TilemapTexture, TilemapTileSize}, tiles::{TileBundle, TilePos, TileStorage}, TilemapBundle, TilemapPlugin};
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
-use crate::{ofm_api::{buffer_to_bevy_image, get_ofm_data}, tile::{world_mercator_to_lat_lon, Coord}, STARTING_DISPLACEMENT, STARTING_LONG_LAT, TILE_QUALITY};
+use crate::{camera::camera_world_rect, error::TileError, labels::Labels, ofm_api::{buffer_to_bevy_image, failed_tile_data, OfmTiles}, style::ActiveStyle, projection::{LonLat, Tile, WorldSpace}, tile_source::ActiveTileSource, worker_pool::{ChunkResult, TileJob, TileWorkerPool, WorkerPoolSettings}, STARTING_DISPLACEMENT, TILE_QUALITY};
 
 // For this example, don't choose too large a chunk size.
 const CHUNK_SIZE: UVec2 = UVec2 { x: 1, y: 1 };
+// Not much to see further out, the whole world is a handful of tiles
+const MIN_ZOOM_LEVEL: u32 = 3;
+// Past the source's last level tiles are cut from their ancestors there and drawn bigger, up to this level
+const MAX_ZOOM_LEVEL: u32 = 20;
+// Failed tiles are retried after this many seconds, doubling on every failure up to the maximum
+const RETRY_DELAY_SECS: f32 = 2.0;
+const MAX_RETRY_DELAY_SECS: f32 = 60.0;
+// How many chunks past the edge of the screen are loaded ahead of panning, unless set with `--prefetch`
+const DEFAULT_PREFETCH: i32 = 1;
+// Loaded chunks are only despawned this many chunks past the prefetched ones, so panning back doesn't reload them
+const KEEP_MARGIN: i32 = 2;
+// Tiles from the levels we zoomed away from stay under the new ones until they're in, for this many levels
+const MAX_FALLBACK_LEVELS: u32 = 2;
+// How many levels up to look for a loaded tile to stand in for a missing one
+const MAX_ANCESTOR_LEVELS: u32 = 4;
+// The projection scale at which the next tile level takes over, a bit past half way so it doesn't flip back and forth
+const ZOOM_OUT_SCALE: f32 = 1.5;
+const ZOOM_IN_SCALE: f32 = 1.0 / ZOOM_OUT_SCALE;
+// Once the camera is this many tiles from the world origin the origin is moved under it, to keep f32 positions precise
+const REBASE_DISTANCE: f32 = 32.0;
 
 pub struct TileMapPlugin;
 
 impl Plugin for TileMapPlugin {
     fn build(&self, app: &mut App) {
-        let (tx, rx): (Sender<(IVec2, Vec<u8>)>, Receiver<(IVec2, Vec<u8>)>) = bounded(10);
+        // The pool keeps the number of jobs in flight down, so the workers never have to wait on the map
+        let (tx, rx): (Sender<ChunkResult>, Receiver<ChunkResult>) = unbounded();
         app.insert_resource(ChunkReceiver(rx))  // Store receiver globally
             .insert_resource(ChunkSender(tx))
             .add_plugins(TilemapPlugin)
             .insert_resource(ChunkManager::default())
             .insert_resource(ZoomManager::default())
-            .add_systems(Update, (spawn_chunks_around_camera, spawn_to_needed_chunks))
-            .add_systems(Update, detect_zoom_level)
+            .init_resource::<ActiveTileSource>()
+            .init_resource::<ActiveStyle>()
+            .init_resource::<Labels>()
+            .init_resource::<WorkerPoolSettings>()
+            .init_resource::<OfmTiles>()
+            .init_resource::<PrefetchSettings>()
+            .add_systems(PreStartup, apply_source_metadata)
+            .add_systems(Startup, (setup_failed_tile_image, setup_worker_pool, setup_attribution))
+            .add_systems(Update, map_update_systems())
             .add_systems(FixedUpdate, (despawn_outofrange_chunks, read_map_receiver));
     }
 }
 
+/// The map's per frame systems. The level and origin are settled first,
+/// so chunks spawned in the same frame are placed against the origin they'll be shown with.
+fn map_update_systems() -> SystemConfigs {
+    (
+        (detect_zoom_level, rebase_origin),
+        (spawn_chunks_around_camera, spawn_to_needed_chunks, retry_failed_chunks, despawn_covered_fallbacks, redraw_for_labels),
+    )
+        .chain()
+}
+
 #[derive(Debug, Resource, Clone)]
 pub struct ZoomManager {
+    /// The level tiles are loaded at
     pub zoom_level: u32,
+    /// What is actually on screen, the tile level scaled by the camera projection
+    pub zoom: f32,
     pub last_zoom_level: u32,
     pub last_projection_level: f32,
-    pub tile_size: f32
+    pub tile_size: f32,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
 }
 
 impl Default for ZoomManager {
     fn default() -> Self {
         Self {
             zoom_level: 14,
+            zoom: 14.0,
             last_zoom_level: 0,
             last_projection_level: 0.0,
-            tile_size: TILE_QUALITY as f32
+            tile_size: TILE_QUALITY as f32,
+            min_zoom: MIN_ZOOM_LEVEL,
+            max_zoom: MAX_ZOOM_LEVEL,
         }
     }
 }
@@ -48,9 +92,33 @@ impl Default for ZoomManager {
 #[derive(Debug, Resource)]
 pub struct ChunkManager {
     pub spawned_chunks: HashSet<IVec2>,
-    pub to_spawn_chunks: HashMap<IVec2, Vec<u8>>, // Store raw image data
+    pub to_spawn_chunks: HashMap<IVec2, ChunkResult>, // Store raw image data
+    pub failed_chunks: HashMap<IVec2, FailedChunk>,
+    /// Stand ins cut from a lower zoom tile, for chunks whose own tile isn't in yet
+    pub placeholders: HashMap<IVec2, Entity>,
     pub update: bool, // Store raw image data
-    pub refrence_long_lat: Coord,
+    /// World space is centred on the middle of this tile, chunk positions are tile positions with y flipped
+    pub origin: Tile,
+    /// Goes up on every zoom change, so tiles requested before it can be told apart
+    pub generation: u64,
+    /// The chunks on screen plus the prefetch margin, as of the last time chunks were requested
+    pub view: IRect,
+}
+
+impl ChunkManager {
+    /// The world space tiles of the current level are laid out in
+    pub fn world_space(&self, zoom_manager: &ZoomManager) -> WorldSpace {
+        WorldSpace {
+            origin: self.origin.center().to_mercator(),
+            zoom: self.origin.zoom,
+            tile_size: zoom_manager.tile_size as f64,
+        }
+    }
+
+    /// The chunk at the world origin
+    pub fn origin_chunk(&self) -> IVec2 {
+        tile_to_chunk_pos(self.origin)
+    }
 }
 
 impl Default for ChunkManager {
@@ -58,15 +126,70 @@ impl Default for ChunkManager {
         Self {
             spawned_chunks: HashSet::default(),
             to_spawn_chunks: HashMap::default(),
+            failed_chunks: HashMap::default(),
+            placeholders: HashMap::default(),
             update: true,
-            refrence_long_lat: STARTING_LONG_LAT,
+            origin: STARTING_DISPLACEMENT.to_tile(14),
+            generation: 0,
+            view: IRect::default(),
+        }
+    }
+}
+
+/// How many chunks past the edge of the screen are loaded ahead of time, set with `--prefetch <chunks>`
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct PrefetchSettings {
+    pub margin: i32,
+}
+
+impl Default for PrefetchSettings {
+    fn default() -> Self {
+        Self {
+            margin: DEFAULT_PREFETCH,
+        }
+    }
+}
+
+pub fn prefetch_settings_from_args(args: impl IntoIterator<Item = String>) -> PrefetchSettings {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--prefetch" {
+            match args.next().and_then(|margin| margin.parse::<i32>().ok()) {
+                Some(margin) if margin >= 0 => return PrefetchSettings { margin },
+                _ => warn!("--prefetch expects a number of chunks, 0 or more"),
+            }
         }
     }
+    PrefetchSettings::default()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FailedChunk {
+    pub attempts: u32,
+    /// Elapsed seconds after which the chunk is requested again
+    pub retry_at: f32,
+}
+
+/// Marks the placeholder spawned for a chunk whose tile couldn't be loaded
+#[derive(Component)]
+pub struct FailedTile(pub IVec2);
+
+#[derive(Resource, Deref)]
+pub struct FailedTileImage(Handle<Image>);
+
+/// A tile from a level we zoomed away from, kept until the tiles of the current level cover it
+#[derive(Component)]
+pub struct FallbackTile {
+    /// How many levels away it is
+    pub levels: u32,
 }
 
+#[derive(Component)]
+pub struct PlaceholderTile;
+
 #[derive(Resource, Debug, Clone, Copy, PartialEq)]
 pub struct Location {
-    pub location: Coord,
+    pub location: LonLat,
 }
 
 impl Default for Location {
@@ -77,69 +200,275 @@ impl Default for Location {
     }
 }
 
-fn detect_zoom_level(
-    mut chunk_manager: ResMut<ChunkManager>,
+/// Credits the tile source in the bottom left corner, as its attribution asks
+fn setup_attribution(mut commands: Commands, tile_source: Res<ActiveTileSource>, asset_server: Res<AssetServer>) {
+    let Some(attribution) = tile_source.metadata().attribution else {
+        return;
+    };
+    commands.spawn((
+        Text::new(strip_html_tags(&attribution)),
+        TextFont {
+            font: asset_server.load("fonts/BagnardSans.otf"),
+            font_size: 14.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        },
+    ));
+}
+
+/// Attributions usually come as HTML links, only their text is shown
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&copy;", "©").replace("&amp;", "&").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Lets the tile source decide where the camera starts and how far it can zoom
+fn apply_source_metadata(
+    tile_source: Res<ActiveTileSource>,
     mut zoom_manager: ResMut<ZoomManager>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    mut location_manager: ResMut<Location>,
+) {
+    let metadata = tile_source.metadata();
+    zoom_manager.min_zoom = metadata.min_zoom.max(MIN_ZOOM_LEVEL);
+    // A source's maxzoom doesn't stop zooming, past it tiles are overzoomed from that level up to MAX_ZOOM_LEVEL
+    zoom_manager.max_zoom = metadata.max_zoom.max(MAX_ZOOM_LEVEL).max(zoom_manager.min_zoom);
+    zoom_manager.zoom_level = metadata.center_zoom.unwrap_or(zoom_manager.zoom_level).clamp(zoom_manager.min_zoom, zoom_manager.max_zoom);
+    zoom_manager.zoom = zoom_manager.zoom_level as f32;
+
+    if let Some(location) = metadata.starting_location() {
+        location_manager.location = location;
+    }
+    chunk_manager.origin = location_manager.location.to_tile(zoom_manager.zoom_level);
+}
+
+/// Every chunk on the map, fallbacks and failed ones too
+type ChunkTiles<'w, 's> = Query<'w, 's, (Entity, &'static mut Transform, Option<&'static mut FallbackTile>, Has<FailedTile>), (With<TileStorage>, Without<Camera>)>;
+/// Everything that moves with the origin
+type MapLayers = (Or<(With<TileStorage>, With<PlaceholderTile>)>, Without<Camera>);
+/// Chunks showing their own tile
+type LoadedChunks = (With<TileStorage>, With<TileMarker>, Without<FailedTile>);
+
+/// The map state switching tile levels changes
+#[derive(SystemParam)]
+struct MapLevel<'w, 's> {
+    chunk_manager: ResMut<'w, ChunkManager>,
+    zoom_manager: ResMut<'w, ZoomManager>,
+    location_manager: ResMut<'w, Location>,
+    tiles: ChunkTiles<'w, 's>,
+    commands: Commands<'w, 's>,
+    worker_pool: Res<'w, TileWorkerPool>,
+}
+
+fn detect_zoom_level(
+    mut map: MapLevel,
     mut ortho_projection_query: Query<&mut OrthographicProjection, With<Camera>>,
-    chunk_query: Query<(Entity, &TileMarker)>,
     mut camera_query: Query<&mut Transform, With<Camera>>,
-    commands: Commands,
-    location_manager: ResMut<Location>,
 ) {
     if let Ok(mut projection) = ortho_projection_query.get_single_mut() {
         if let Ok(mut camera) = camera_query.get_single_mut() {
-            if projection.scale != zoom_manager.last_projection_level {
-                zoom_manager.last_projection_level = projection.scale;
-                if projection.scale > 1. && projection.scale != 0. && zoom_manager.zoom_level > 3 {
-                    zoom_manager.last_zoom_level = zoom_manager.zoom_level;
-                    zoom_manager.zoom_level -= 1;
-
-                    despawn_all_chunks(commands, chunk_query);
-                    chunk_manager.spawned_chunks.clear();
-                    chunk_manager.to_spawn_chunks.clear();
-
-                    // This ensures that the tile size stays correct
-                    chunk_manager.refrence_long_lat *= Coord {lat: 2., long: 2.};
-
-                    camera.translation = location_manager.location.to_game_coords(chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size.into()).extend(1.0);
-                    
-                    projection.scale = 1.0;
-                } else if projection.scale < 1. && projection.scale != 0. && zoom_manager.zoom_level < 14 {
-                    zoom_manager.last_zoom_level = zoom_manager.zoom_level;
-                    zoom_manager.zoom_level += 1;
-
-                    despawn_all_chunks(commands, chunk_query);
-                    chunk_manager.spawned_chunks.clear();
-                    chunk_manager.to_spawn_chunks.clear();
-
-                    // This ensures that the tile size stays correct
-                    chunk_manager.refrence_long_lat /= Coord {lat: 2., long: 2.};
-                    
-                    camera.translation = location_manager.location.to_game_coords(chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size.into()).extend(1.0);
-
-                    projection.scale = 1.0;
+            if projection.scale != map.zoom_manager.last_projection_level {
+                let previous_world = map.chunk_manager.world_space(&map.zoom_manager);
+                let previous_level = map.zoom_manager.zoom_level;
+                // Where the camera is looking, so the view stays put when the level changes
+                let center = previous_world.world_to_lon_lat(camera.translation.xy());
+                if projection.scale > ZOOM_OUT_SCALE && projection.scale != 0. && map.zoom_manager.zoom_level > map.zoom_manager.min_zoom {
+                    // The new level has half the detail, so the same view needs half the scale
+                    change_zoom_level(-1, 0.5, center, &previous_world, &mut map, &mut projection, &mut camera);
+                } else if projection.scale < ZOOM_IN_SCALE && projection.scale != 0. && map.zoom_manager.zoom_level < map.zoom_manager.max_zoom {
+                    // The new level has twice the detail, so the same view needs twice the scale
+                    change_zoom_level(1, 2.0, center, &previous_world, &mut map, &mut projection, &mut camera);
                 }
-                chunk_manager.update = true;
+                // A big jump can cross more than one level, so look again next frame if this one changed it
+                if map.zoom_manager.zoom_level == previous_level {
+                    map.zoom_manager.last_projection_level = projection.scale;
+                }
+                map.zoom_manager.zoom = map.zoom_manager.zoom_level as f32 - projection.scale.log2();
+                map.chunk_manager.update = true;
             }
         }
     }
 }
 
+/// Moves to the tile level `step` away, keeping the camera over `center` and the current tiles as fallbacks.
+/// `scale_factor` is what the projection scale is multiplied by, so the view looks the same on the new level.
+fn change_zoom_level(
+    step: i32,
+    scale_factor: f32,
+    center: LonLat,
+    previous_world: &WorldSpace,
+    map: &mut MapLevel,
+    projection: &mut OrthographicProjection,
+    camera: &mut Transform,
+) {
+    let MapLevel { chunk_manager, zoom_manager, location_manager, tiles, commands, worker_pool } = map;
+    zoom_manager.last_zoom_level = zoom_manager.zoom_level;
+    zoom_manager.zoom_level = zoom_manager.zoom_level.saturating_add_signed(step);
+
+    chunk_manager.spawned_chunks.clear();
+    chunk_manager.to_spawn_chunks.clear();
+    chunk_manager.failed_chunks.clear();
+    for (_, placeholder) in chunk_manager.placeholders.drain() {
+        commands.entity(placeholder).despawn();
+    }
+    chunk_manager.generation += 1;
+    worker_pool.start_generation(chunk_manager.generation);
+
+    // The new level gets its own origin, right where the camera is
+    chunk_manager.origin = center.to_tile(zoom_manager.zoom_level);
+    let world = chunk_manager.world_space(zoom_manager);
+    keep_as_fallback(commands, tiles, previous_world, &world);
+
+    location_manager.location = center;
+    camera.translation = world.lon_lat_to_world(center).extend(camera.translation.z);
+    projection.scale *= scale_factor;
+}
+
+/// Moves the world origin under the camera once it has wandered far from it, and everything on the map with it
+fn rebase_origin(
+    mut chunk_manager: ResMut<ChunkManager>,
+    zoom_manager: Res<ZoomManager>,
+    mut camera_query: Query<&mut Transform, With<Camera>>,
+    mut map_query: Query<&mut Transform, MapLayers>,
+) {
+    let Ok(mut camera) = camera_query.get_single_mut() else {
+        return;
+    };
+    let tile_size = zoom_manager.tile_size;
+    if camera.translation.xy().length() < REBASE_DISTANCE * tile_size {
+        return;
+    }
+
+    // By whole chunks, so the chunks stay on the grid
+    let shift = (camera.translation.xy() / tile_size).round().as_ivec2();
+    let origin = chunk_manager.origin_chunk() + shift;
+    chunk_manager.origin = chunk_pos_to_tile(origin, chunk_manager.origin.zoom);
+
+    let offset = (shift.as_vec2() * tile_size).extend(0.0);
+    camera.translation -= offset;
+    for mut transform in map_query.iter_mut() {
+        transform.translation -= offset;
+    }
+}
+
 #[derive(Resource, Deref)]
-pub struct ChunkReceiver(Receiver<(IVec2, Vec<u8>)>); // Use Vec<u8> for raw image data
+pub struct ChunkReceiver(Receiver<ChunkResult>); // Use Vec<u8> for raw image data
 
 #[derive(Resource, Deref)]
-pub struct ChunkSender(Sender<(IVec2, Vec<u8>)>);
+pub struct ChunkSender(Sender<ChunkResult>);
 
 #[derive(Component)]
 pub struct TileMarker;
 
+/// Turns the tiles of the level we just left into fallbacks, moved and scaled to line up with the new level
+fn keep_as_fallback(
+    commands: &mut Commands,
+    tiles: &mut ChunkTiles,
+    from: &WorldSpace,
+    to: &WorldSpace,
+) {
+    let scale = 2_f32.powi(to.zoom as i32 - from.zoom as i32);
+    for (entity, mut transform, fallback, failed) in tiles.iter_mut() {
+        let levels = fallback.as_ref().map_or(0, |fallback| fallback.levels) + 1;
+        if failed || levels > MAX_FALLBACK_LEVELS {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        // Tilemaps are centred on their translation, so moving the centre and scaling around it lines them up
+        transform.translation = to.to_world(from.to_mercator(transform.translation.xy())).extend(-(levels as f32));
+        transform.scale *= Vec3::new(scale, scale, 1.0);
+        match fallback {
+            Some(mut fallback) => fallback.levels = levels,
+            None => {
+                commands.entity(entity).remove::<TileMarker>().insert(FallbackTile { levels });
+            }
+        }
+    }
+}
+
+/// Takes down fallback tiles once the chunks under them are loaded or out of view
+fn despawn_covered_fallbacks(
+    mut commands: Commands,
+    fallbacks: Query<(Entity, &Transform), With<FallbackTile>>,
+    tiles: Query<&Transform, LoadedChunks>,
+    chunk_manager: Res<ChunkManager>,
+    zoom_manager: Res<ZoomManager>,
+) {
+    let tile_size = zoom_manager.tile_size;
+    let origin = chunk_manager.origin_chunk();
+    let loaded: HashSet<IVec2> = tiles.iter().map(|transform| camera_pos_to_chunk_pos(&transform.translation.xy(), origin, tile_size)).collect();
+
+    for (entity, transform) in fallbacks.iter() {
+        let half = tile_size * transform.scale.x / 2.0;
+        let min = camera_pos_to_chunk_pos(&(transform.translation.xy() - half), origin, tile_size);
+        let max = camera_pos_to_chunk_pos(&(transform.translation.xy() + half), origin, tile_size);
+        let covered = (min.y..=max.y).all(|y| {
+            (min.x..=max.x).all(|x| {
+                let chunk_pos = IVec2::new(x, y);
+                loaded.contains(&chunk_pos)
+                    || !chunk_manager.view.contains(chunk_pos)
+                    || chunk_pos_to_tile(chunk_pos, chunk_manager.origin.zoom).wrapped().is_none()
+            })
+        });
+        if covered {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Shows the part of an already loaded lower zoom tile that covers a chunk, until the chunk's own tile is in
+fn spawn_ancestor_placeholder(
+    commands: &mut Commands,
+    tile_images: &OfmTiles,
+    style_version: u64,
+    chunk_pos: IVec2,
+    origin: IVec2,
+    job: &TileJob,
+    tile_size: f32,
+) -> Option<Entity> {
+    let (levels, image) = tile_images.ancestor((style_version, job.zoom, job.x, job.y), MAX_ANCESTOR_LEVELS)?;
+
+    // The ancestor is split into 2^levels parts each way, pick the one this tile is
+    let part = tile_size / (1_u64 << levels) as f32;
+    let mask = (1_u64 << levels) - 1;
+    let min = Vec2::new((job.x & mask) as f32 * part, (job.y & mask) as f32 * part);
+    let entity = commands
+        .spawn((
+            Sprite {
+                image,
+                rect: Some(Rect::from_corners(min, min + part)),
+                custom_size: Some(Vec2::splat(tile_size)),
+                ..default()
+            },
+            Transform::from_translation(chunk_pos_to_world_pos(chunk_pos, origin, tile_size).extend(-0.5)),
+            PlaceholderTile,
+        ))
+        .id();
+    Some(entity)
+}
+
 fn spawn_chunk(
     commands: &mut Commands,
     tile: Handle<Image>,
     chunk_pos: IVec2,
+    origin: IVec2,
     tile_size: f32,
-) {
+) -> Entity {
     let tilemap_entity = commands.spawn_empty().id();
     let mut tile_storage = TileStorage::empty(CHUNK_SIZE.into());
 
@@ -155,11 +484,7 @@ fn spawn_chunk(
     commands.entity(tilemap_entity).add_child(tile_entity);
     tile_storage.set(&tile_pos, tile_entity);
 
-    let transform = Transform::from_translation(Vec3::new(
-        chunk_pos.x as f32 * CHUNK_SIZE.x as f32 * tile_size,
-        chunk_pos.y as f32 * CHUNK_SIZE.y as f32 * tile_size,
-        0.0,
-    ));
+    let transform = Transform::from_translation(chunk_pos_to_world_pos(chunk_pos, origin, tile_size).extend(0.0));
 
     commands.entity(tilemap_entity).insert(TilemapBundle {
         grid_size: TilemapGridSize::from(TilemapTileSize { x: tile_size, y: tile_size }),
@@ -170,62 +495,149 @@ fn spawn_chunk(
         transform,
         ..Default::default()
     }).insert(TileMarker);
+    tilemap_entity
 }
 
-fn camera_pos_to_chunk_pos(camera_pos: &Vec2, tile_size: f32) -> IVec2 {
+/// Chunks are tiles with y going up instead of down
+fn tile_to_chunk_pos(tile: Tile) -> IVec2 {
+    IVec2::new(tile.x, -tile.y)
+}
+
+fn chunk_pos_to_tile(chunk_pos: IVec2, zoom: u32) -> Tile {
+    Tile::new(chunk_pos.x, -chunk_pos.y, zoom)
+}
+
+/// Tilemaps are centred on their translation, so a chunk covers half a chunk either side of it
+fn camera_pos_to_chunk_pos(camera_pos: &Vec2, origin: IVec2, tile_size: f32) -> IVec2 {
     let chunk_size = Vec2::new(
         CHUNK_SIZE.x as f32 * tile_size,
         CHUNK_SIZE.y as f32 * tile_size,
     );
     let camera_pos = Vec2::new(camera_pos.x, camera_pos.y) / chunk_size;
-    camera_pos.floor().as_ivec2()
+    camera_pos.round().as_ivec2() + origin
+}
+
+/// The chunks a world space rectangle touches
+fn visible_chunks(rect: Rect, origin: IVec2, tile_size: f32) -> IRect {
+    IRect::from_corners(
+        camera_pos_to_chunk_pos(&rect.min, origin, tile_size),
+        camera_pos_to_chunk_pos(&rect.max, origin, tile_size),
+    )
 }
 
+/// Cuts the view down to one copy of the world around the camera, as zoomed far out it repeats without end,
+/// and to the rows between the poles
+fn clamp_to_world(view: IRect, center: IVec2, zoom: u32) -> IRect {
+    let tiles = 1_i32 << zoom;
+    let (min_x, max_x) = if view.width() >= tiles {
+        let min_x = center.x - tiles / 2;
+        (min_x, min_x + tiles - 1)
+    } else {
+        (view.min.x, view.max.x)
+    };
+    // Chunk y is tile y flipped, so the rows go from 1 - tiles in the south up to 0 in the north
+    IRect {
+        min: IVec2::new(min_x, view.min.y.max(1 - tiles)),
+        max: IVec2::new(max_x, view.max.y.min(0)),
+    }
+}
 
-fn chunk_pos_to_world_pos(chunk_pos: IVec2, tile_size: f32) -> Vec2 {
+fn chunk_pos_to_world_pos(chunk_pos: IVec2, origin: IVec2, tile_size: f32) -> Vec2 {
     let chunk_size = Vec2::new(
         CHUNK_SIZE.x as f32 * tile_size,
         CHUNK_SIZE.y as f32 * tile_size,
     );
+    let chunk_pos = chunk_pos - origin;
     Vec2::new(
         chunk_pos.x as f32 * chunk_size.x,
         chunk_pos.y as f32 * chunk_size.y,
     )
 }
 
+/// The camera and the window it shows the map in
+#[derive(SystemParam)]
+struct MapView<'w, 's> {
+    camera: Query<'w, 's, (&'static Transform, &'static OrthographicProjection), With<Camera>>,
+    window: Query<'w, 's, &'static Window, With<PrimaryWindow>>,
+}
+
+/// The rasterized tiles kept in memory, the style they're drawn with and the labels placed in them
+#[derive(SystemParam)]
+struct TileImages<'w> {
+    cache: ResMut<'w, OfmTiles>,
+    style: Res<'w, ActiveStyle>,
+    labels: Res<'w, Labels>,
+}
+
 fn spawn_chunks_around_camera(
-    camera_query: Query<&Transform, With<Camera>>,
-    chunk_sender: Res<ChunkSender>,  // Use the stored sender
+    mut commands: Commands,
+    map_view: MapView,
+    worker_pool: Res<TileWorkerPool>,
+    mut tile_images: TileImages,
+    prefetch: Res<PrefetchSettings>,
     mut chunk_manager: ResMut<ChunkManager>,
     zoom_manager: Res<ZoomManager>,
 ) {
-    if chunk_manager.update {
-        chunk_manager.update = false;
-        for transform in camera_query.iter() {
-            let camera_chunk_pos = camera_pos_to_chunk_pos(&transform.translation.xy(), zoom_manager.tile_size);
-            let range = 2;
-
-            for y in (camera_chunk_pos.y - range)..=(camera_chunk_pos.y + range) {
-                for x in (camera_chunk_pos.x - range)..=(camera_chunk_pos.x + range) {
-                    let chunk_pos = IVec2::new(x, y);
-                    if !chunk_manager.spawned_chunks.contains(&chunk_pos) {
-                        let tx = chunk_sender.clone(); // Clone existing sender
-                        let zoom_manager = zoom_manager.clone();
-                        let world_pos = chunk_pos_to_world_pos(chunk_pos, zoom_manager.tile_size);
-                        let position = world_mercator_to_lat_lon(world_pos.x.into(), world_pos.y.into(), chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size);
-
-                        thread::spawn(move || {
-                            let tile_coords = position.to_tile_coords(zoom_manager.zoom_level);
-
-                            let tile_image = get_ofm_data(tile_coords.x as u64, tile_coords.y as u64, zoom_manager.zoom_level as u64, zoom_manager.tile_size as u32);
-                            if let Err(e) = tx.send((chunk_pos, tile_image)) {
-                                eprintln!("Failed to send chunk data: {:?}", e);
-                            }
-                        });
-
-                        chunk_manager.spawned_chunks.insert(chunk_pos);
-                    }
+    let (Ok((transform, projection)), Ok(window)) = (map_view.camera.get_single(), map_view.window.get_single()) else {
+        return;
+    };
+    let origin = chunk_manager.origin_chunk();
+    let camera_chunk_pos = camera_pos_to_chunk_pos(&transform.translation.xy(), origin, zoom_manager.tile_size);
+    let view = visible_chunks(camera_world_rect(transform.translation.xy(), window, projection), origin, zoom_manager.tile_size)
+        .inflate(prefetch.margin);
+    let view = clamp_to_world(view, camera_chunk_pos, zoom_manager.zoom_level);
+    // Key panning and window resizes move the view without anything asking for an update
+    if !chunk_manager.update && view == chunk_manager.view {
+        return;
+    }
+    chunk_manager.update = false;
+    chunk_manager.view = view;
+
+    worker_pool.set_center(camera_chunk_pos);
+
+    // Chunks that went out of view before their tile was done are requested again if they come back
+    for job in worker_pool.cancel(|job| view.contains(job.chunk_pos)) {
+        // The chunk is still there, just without the neighbour's label
+        if job.redraw {
+            continue;
+        }
+        chunk_manager.spawned_chunks.remove(&job.chunk_pos);
+        if let Some(placeholder) = chunk_manager.placeholders.remove(&job.chunk_pos) {
+            commands.entity(placeholder).despawn();
+        }
+    }
+
+    for y in view.min.y..=view.max.y {
+        for x in view.min.x..=view.max.x {
+            let chunk_pos = IVec2::new(x, y);
+            if !chunk_manager.spawned_chunks.contains(&chunk_pos) {
+                // The map repeats east and west, but ends at the poles
+                let Some(tile_coords) = chunk_pos_to_tile(chunk_pos, zoom_manager.zoom_level).wrapped() else {
+                    continue;
+                };
+
+                // Drawn before and still in memory, no need to go through the workers
+                if let Some(image) = tile_images.cache.get((tile_images.style.version, zoom_manager.zoom_level, tile_coords.x as u64, tile_coords.y as u64)) {
+                    spawn_chunk(&mut commands, image, chunk_pos, origin, zoom_manager.tile_size);
+                    chunk_manager.spawned_chunks.insert(chunk_pos);
+                    continue;
                 }
+
+                let job = TileJob {
+                    chunk_pos,
+                    generation: chunk_manager.generation,
+                    x: tile_coords.x as u64,
+                    y: tile_coords.y as u64,
+                    zoom: zoom_manager.zoom_level,
+                    tile_size: zoom_manager.tile_size as u32,
+                    redraw: false,
+                };
+                if let Some(placeholder) = spawn_ancestor_placeholder(&mut commands, &tile_images.cache, tile_images.style.version, chunk_pos, origin, &job, zoom_manager.tile_size) {
+                    chunk_manager.placeholders.insert(chunk_pos, placeholder);
+                }
+                worker_pool.submit(job);
+
+                chunk_manager.spawned_chunks.insert(chunk_pos);
             }
         }
     }
@@ -234,12 +646,26 @@ fn spawn_chunks_around_camera(
 fn read_map_receiver(
     map_receiver: Res<ChunkReceiver>,
     mut chunk_manager: ResMut<ChunkManager>,
+    tile_images: Res<OfmTiles>,
+    style: Res<ActiveStyle>,
+    labels: Res<Labels>,
 ) {
     let mut new_chunks = Vec::new();
 
-    while let Ok((chunk_pos, raw_image_data)) = map_receiver.try_recv() {
-        if !chunk_manager.to_spawn_chunks.contains_key(&chunk_pos) {
-            new_chunks.push((chunk_pos, raw_image_data));
+    while let Ok(result) = map_receiver.try_recv() {
+        // Drawn for a zoom level we've since left, or for a chunk that went out of view
+        if result.job.generation != chunk_manager.generation || matches!(result.data, Err(TileError::Cancelled)) {
+            // Its labels were placed, but the image they are in is thrown away.
+            // A newer drawing of the tile may have started since, it keeps them.
+            let ChunkResult { job, drawing, data } = result;
+            let drawn = data.is_ok() || matches!(data, Err(TileError::Cancelled));
+            if drawn && !tile_images.contains((style.version, job.zoom, job.x, job.y)) {
+                labels.forget_drawing(job.zoom, job.x, job.y, drawing);
+            }
+            continue;
+        }
+        if !chunk_manager.to_spawn_chunks.contains_key(&result.job.chunk_pos) {
+            new_chunks.push((result.job.chunk_pos, result));
         }
     }
 
@@ -252,43 +678,251 @@ fn spawn_to_needed_chunks(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
     mut chunk_manager: ResMut<ChunkManager>,
+    mut tile_images: TileImages,
     zoom_manager: Res<ZoomManager>,
+    failed_tile_image: Res<FailedTileImage>,
+    time: Res<Time>,
 ) {
-    let to_spawn_chunks: Vec<(IVec2, Vec<u8>)> = chunk_manager.to_spawn_chunks.iter().map(|(pos, data)| (*pos, data.clone())).collect();
-    for (chunk_pos, raw_image_data) in to_spawn_chunks {
-        let tile_handle = images.add(buffer_to_bevy_image(raw_image_data, zoom_manager.tile_size as u32));
-        spawn_chunk(&mut commands, tile_handle, chunk_pos, zoom_manager.tile_size);
+    let origin = chunk_manager.origin_chunk();
+    let to_spawn_chunks: Vec<(IVec2, ChunkResult)> = chunk_manager.to_spawn_chunks.drain().collect();
+    for (chunk_pos, ChunkResult { job, data, .. }) in to_spawn_chunks {
+        if job.redraw {
+            // The chunks showing the tile pick up the new image, a failed redraw just leaves the old one
+            if let (Ok(raw_image_data), Some(handle)) = (data, tile_images.cache.get((tile_images.style.version, job.zoom, job.x, job.y))) {
+                images.insert(&handle, buffer_to_bevy_image(raw_image_data, zoom_manager.tile_size as u32));
+            }
+            continue;
+        }
+        if let Some(placeholder) = chunk_manager.placeholders.remove(&chunk_pos) {
+            commands.entity(placeholder).despawn();
+        }
+        match data {
+            Ok(raw_image_data) => {
+                let bytes = raw_image_data.len();
+                let tile_handle = images.add(buffer_to_bevy_image(raw_image_data, zoom_manager.tile_size as u32));
+                for (_, zoom, x, y) in tile_images.cache.insert((tile_images.style.version, job.zoom, job.x, job.y), tile_handle.clone(), bytes) {
+                    tile_images.labels.forget_tile(zoom, x, y);
+                }
+                spawn_chunk(&mut commands, tile_handle, chunk_pos, origin, zoom_manager.tile_size);
+                chunk_manager.failed_chunks.remove(&chunk_pos);
+            }
+            Err(e) => {
+                let failed = chunk_manager.failed_chunks.entry(chunk_pos).or_insert(FailedChunk { attempts: 0, retry_at: 0.0 });
+                failed.attempts += 1;
+                let delay = (RETRY_DELAY_SECS * 2_f32.powi(failed.attempts as i32 - 1)).min(MAX_RETRY_DELAY_SECS);
+                failed.retry_at = time.elapsed_secs() + delay;
+                warn!("Failed to load chunk {} ({}), retrying in {}s", chunk_pos, e, delay);
+
+                let entity = spawn_chunk(&mut commands, failed_tile_image.clone(), chunk_pos, origin, zoom_manager.tile_size);
+                commands.entity(entity).insert(FailedTile(chunk_pos));
+            }
+        }
         chunk_manager.spawned_chunks.insert(chunk_pos);
     }
-    chunk_manager.to_spawn_chunks.clear();
+}
+
+fn setup_worker_pool(
+    mut commands: Commands,
+    settings: Res<WorkerPoolSettings>,
+    chunk_sender: Res<ChunkSender>,
+    tile_source: Res<ActiveTileSource>,
+    style: Res<ActiveStyle>,
+    labels: Res<Labels>,
+) {
+    info!("Starting {} tile workers", settings.workers);
+    let pool = TileWorkerPool::new(*settings, tile_source.clone(), style.clone(), labels.clone(), chunk_sender.0.clone());
+    commands.insert_resource(pool);
+}
+
+fn setup_failed_tile_image(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    zoom_manager: Res<ZoomManager>,
+) {
+    let tile_size = zoom_manager.tile_size as u32;
+    let handle = images.add(buffer_to_bevy_image(failed_tile_data(tile_size), tile_size));
+    commands.insert_resource(FailedTileImage(handle));
+}
+
+/// Takes down the placeholders of failed chunks that are due, so they get requested again
+fn retry_failed_chunks(
+    mut commands: Commands,
+    mut chunk_manager: ResMut<ChunkManager>,
+    failed_tiles: Query<(Entity, &FailedTile)>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_secs();
+    for (entity, failed_tile) in failed_tiles.iter() {
+        let due = chunk_manager.failed_chunks.get(&failed_tile.0).is_none_or(|failed| failed.retry_at <= now);
+        if due {
+            chunk_manager.spawned_chunks.remove(&failed_tile.0);
+            chunk_manager.update = true;
+            commands.entity(entity).despawn_recursive();
+        }
+    }
 }
 
 fn despawn_outofrange_chunks(
     mut commands: Commands,
-    camera_query: Query<&Transform, With<Camera>>,
-    chunks_query: Query<(Entity, &Transform, &TileMarker)>,
+    chunks_query: Query<(Entity, &Transform), With<TileMarker>>,
     mut chunk_manager: ResMut<ChunkManager>,
     zoom_manager: Res<ZoomManager>,
+    tile_images: Res<OfmTiles>,
+    style: Res<ActiveStyle>,
+    labels: Res<Labels>,
 ) {
-    for camera_transform in camera_query.iter() {
-        for (entity, chunk_transform, _) in chunks_query.iter() {
-            let chunk_pos = chunk_transform.translation.xy();
-            let distance = camera_transform.translation.xy().distance(chunk_pos);
-            if distance > zoom_manager.tile_size * 10.{
-                let x = (chunk_pos.x / (CHUNK_SIZE.x as f32 * zoom_manager.tile_size)).floor() as i32;
-                let y = (chunk_pos.y / (CHUNK_SIZE.y as f32 * zoom_manager.tile_size)).floor() as i32;
-                chunk_manager.spawned_chunks.remove(&IVec2::new(x, y));
-                commands.entity(entity).despawn_recursive();
+    let origin = chunk_manager.origin_chunk();
+    let keep = chunk_manager.view.inflate(KEEP_MARGIN);
+    for (entity, chunk_transform) in chunks_query.iter() {
+        let chunk_pos = camera_pos_to_chunk_pos(&chunk_transform.translation.xy(), origin, zoom_manager.tile_size);
+        if !keep.contains(chunk_pos) {
+            chunk_manager.spawned_chunks.remove(&chunk_pos);
+            commands.entity(entity).despawn_recursive();
+            if let Some(tile) = chunk_pos_to_tile(chunk_pos, zoom_manager.zoom_level).wrapped() {
+                forget_labels_unless_cached(&labels, &tile_images, style.version, tile.zoom, tile.x as u64, tile.y as u64);
             }
         }
     }
 }
 
-fn despawn_all_chunks(
-    mut commands: Commands,
-    chunks_query: Query<(Entity, &TileMarker)>,
+/// Draws tiles again once a label placed in a neighbour reaches into them
+fn redraw_for_labels(
+    chunk_manager: Res<ChunkManager>,
+    zoom_manager: Res<ZoomManager>,
+    mut tile_images: ResMut<OfmTiles>,
+    style: Res<ActiveStyle>,
+    labels: Res<Labels>,
+    worker_pool: Res<TileWorkerPool>,
 ) {
-    for (entity, _) in chunks_query.iter() {
-        commands.entity(entity).despawn_recursive();
+    let view = chunk_manager.view;
+    for (zoom, x, y) in labels.take_redraws() {
+        let key = (style.version, zoom, x, y);
+        if !tile_images.contains(key) {
+            continue;
+        }
+        if zoom != zoom_manager.zoom_level {
+            // A level we've left, it is drawn from scratch if we come back
+            tile_images.remove(key);
+            labels.forget_tile(zoom, x, y);
+            continue;
+        }
+        // The first copy from the left of the view, the map repeats east and west
+        let tiles = 1_i64 << zoom;
+        let chunk_pos = IVec2::new(view.min.x + (x as i64 - view.min.x as i64).rem_euclid(tiles) as i32, -(y as i32));
+        worker_pool.submit(TileJob {
+            chunk_pos,
+            generation: chunk_manager.generation,
+            x,
+            y,
+            zoom,
+            tile_size: zoom_manager.tile_size as u32,
+            redraw: true,
+        });
+    }
+}
+
+/// A tile's labels stay in the index as long as its image is kept, as it can be shown again without being redrawn
+fn forget_labels_unless_cached(labels: &Labels, tile_images: &OfmTiles, style_version: u64, zoom: u32, x: u64, y: u64) {
+    if !tile_images.contains((style_version, zoom, x, y)) {
+        labels.forget_tile(zoom, x, y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tile_source::MemorySource;
+
+    use super::*;
+
+    fn irect(min: (i32, i32), max: (i32, i32)) -> IRect {
+        IRect {
+            min: IVec2::new(min.0, min.1),
+            max: IVec2::new(max.0, max.1),
+        }
+    }
+
+    #[test]
+    fn visible_chunks_around_the_origin() {
+        let rect = Rect::new(-300.0, -100.0, 100.0, 600.0);
+        assert_eq!(visible_chunks(rect, IVec2::new(10, -5), 256.0), irect((9, -5), (10, -3)));
+        // Chunks are placed by their centres, so half a tile past one is the next
+        let rect = Rect::new(-129.0, -127.0, 129.0, 127.0);
+        assert_eq!(visible_chunks(rect, IVec2::ZERO, 256.0), irect((-1, 0), (1, 0)));
+    }
+
+    #[test]
+    fn views_are_clamped_to_one_world() {
+        // The whole world is one tile at zoom 0, and two by two at zoom 1
+        assert_eq!(clamp_to_world(irect((-3, -2), (3, 2)), IVec2::ZERO, 0), irect((0, 0), (0, 0)));
+        assert_eq!(clamp_to_world(irect((-5, -4), (5, 3)), IVec2::new(1, -1), 1), irect((0, -1), (1, 0)));
+        // Over the antimeridian the copy around the camera is kept, chunk 2 being tile 0 again
+        assert_eq!(clamp_to_world(irect((-5, -4), (5, 3)), IVec2::new(2, 0), 1), irect((1, -1), (2, 0)));
+        assert_eq!(clamp_to_world(irect((-4, -7), (4, 0)), IVec2::ZERO, 3), irect((-4, -7), (3, 0)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn views_within_the_world_wrap_in_x_and_stop_at_the_poles() {
+        // Narrower than the world, x runs on past the antimeridian either way
+        assert_eq!(clamp_to_world(irect((6, -3), (9, -1)), IVec2::new(8, -2), 3), irect((6, -3), (9, -1)));
+        assert_eq!(clamp_to_world(irect((-2, -3), (1, -1)), IVec2::new(0, -2), 3), irect((-2, -3), (1, -1)));
+        // but y stops at the top row in the north and the bottom row in the south
+        assert_eq!(clamp_to_world(irect((2, -2), (4, 3)), IVec2::new(3, 0), 3), irect((2, -2), (4, 0)));
+        assert_eq!(clamp_to_world(irect((2, -10), (4, -5)), IVec2::new(3, -7), 3), irect((2, -7), (4, -5)));
+    }
+
+    #[test]
+    fn chunks_spawned_in_a_rebase_frame_use_the_new_origin() {
+        let mut app = App::new();
+        let zoom_manager = ZoomManager::default();
+        let tile_size = zoom_manager.tile_size;
+        let (sender, _receiver) = unbounded();
+        let style = ActiveStyle::default();
+        let labels = Labels::default();
+        let settings = WorkerPoolSettings { workers: 1 };
+        let worker_pool = TileWorkerPool::new(settings, ActiveTileSource::new(MemorySource::new()), style.clone(), labels.clone(), sender);
+
+        let mut chunk_manager = ChunkManager::default();
+        let origin = chunk_manager.origin_chunk();
+        // A result waiting to be spawned, far enough out that the camera over it makes the origin move
+        let chunk_pos = origin + IVec2::new(REBASE_DISTANCE as i32 + 8, 0);
+        let tile = chunk_pos_to_tile(chunk_pos, zoom_manager.zoom_level);
+        let job = TileJob {
+            chunk_pos,
+            generation: chunk_manager.generation,
+            x: tile.x as u64,
+            y: tile.y as u64,
+            zoom: zoom_manager.zoom_level,
+            tile_size: tile_size as u32,
+            redraw: false,
+        };
+        let data = failed_tile_data(tile_size as u32);
+        chunk_manager.to_spawn_chunks.insert(chunk_pos, ChunkResult { job, drawing: 0, data: Ok(data) });
+
+        let mut images = Assets::<Image>::default();
+        let failed_tile_image = FailedTileImage(images.add(buffer_to_bevy_image(failed_tile_data(tile_size as u32), tile_size as u32)));
+        app.insert_resource(chunk_manager)
+            .insert_resource(zoom_manager)
+            .insert_resource(worker_pool)
+            .insert_resource(style)
+            .insert_resource(labels)
+            .insert_resource(images)
+            .insert_resource(failed_tile_image)
+            .insert_resource(Location::default())
+            .insert_resource(Time::<()>::default())
+            .init_resource::<OfmTiles>()
+            .init_resource::<PrefetchSettings>()
+            .add_systems(Update, map_update_systems());
+        app.world_mut().spawn((Window::default(), PrimaryWindow));
+        let camera_pos = chunk_pos_to_world_pos(chunk_pos, origin, tile_size);
+        app.world_mut().spawn((Camera2d, Transform::from_translation(camera_pos.extend(0.0))));
+
+        app.update();
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        assert_ne!(chunk_manager.origin_chunk(), origin, "the origin should have moved");
+        let expected = chunk_pos_to_world_pos(chunk_pos, chunk_manager.origin_chunk(), tile_size);
+        let world = app.world_mut();
+        let spawned: Vec<Vec2> = world.query_filtered::<&Transform, With<TileStorage>>().iter(world).map(|transform| transform.translation.xy()).collect();
+        assert_eq!(spawned, vec![expected]);
+    }
+}
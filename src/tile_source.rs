@@ -0,0 +1,394 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+
+use bevy::prelude::*;
+
+use crate::{error::TileError, http::HttpClient, mbtiles::MbTilesSource, pmtiles::PmTilesSource, projection::LonLat, tile_cache::{cache_settings_from_args, CacheMeta, CacheSettings, TileCache}, tilejson::{TileJson, OFM_TILEJSON_URL}};
+
+/// The planet version we were built against, for when OpenFreeMap's TileJSON can't be had
+pub const OFM_URL_TEMPLATE: &str = "https://tiles.openfreemap.org/planet/20250122_001001_pt/{z}/{x}/{y}.pbf";
+
+/// Anything that can hand out the raw bytes of a vector tile for a z/x/y.
+/// Local sources return an empty tile where they have no data.
+pub trait TileSource: Send + Sync {
+    fn fetch(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, TileError>;
+
+    /// What the source knows about itself, used to place the camera and limit zooming
+    fn metadata(&self) -> TileSourceMetadata {
+        TileSourceMetadata::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileSourceMetadata {
+    pub min_zoom: u32,
+    /// The deepest level with tiles, further in they are cut from this level's
+    pub max_zoom: u32,
+    /// West, south, east, north in degrees
+    pub bounds: Option<[f64; 4]>,
+    pub center: Option<LonLat>,
+    pub center_zoom: Option<u32>,
+    /// Who to credit for the tiles, may contain HTML links
+    pub attribution: Option<String>,
+}
+
+impl Default for TileSourceMetadata {
+    fn default() -> Self {
+        Self {
+            min_zoom: 3,
+            max_zoom: 14,
+            bounds: None,
+            center: None,
+            center_zoom: None,
+            attribution: None,
+        }
+    }
+}
+
+impl TileSourceMetadata {
+    /// The center if the source has one, otherwise the middle of its bounds
+    pub fn starting_location(&self) -> Option<LonLat> {
+        self.center.or_else(|| {
+            self.bounds.map(|[west, south, east, north]| LonLat::new((west + east) / 2.0, (south + north) / 2.0))
+        })
+    }
+}
+
+/// The tile source used by the tile map, picked when the `App` is built
+#[derive(Resource, Clone, Deref)]
+pub struct ActiveTileSource(pub Arc<dyn TileSource>);
+
+impl ActiveTileSource {
+    pub fn new(source: impl TileSource + 'static) -> Self {
+        Self(Arc::new(source))
+    }
+}
+
+impl Default for ActiveTileSource {
+    /// The planet version we were built against, without a cache. `source_from_args` resolves the latest one.
+    fn default() -> Self {
+        Self::new(UrlTemplateSource::new(OFM_URL_TEMPLATE))
+    }
+}
+
+/// Fills in the `{z}`, `{x}` and `{y}` placeholders of a tile path or URL
+pub fn fill_template(template: &str, x: u64, y: u64, zoom: u64) -> String {
+    template
+        .replace("{z}", &zoom.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string())
+}
+
+/// Fetches tiles over HTTP from an XYZ URL template such as `https://example.com/{z}/{x}/{y}.pbf`
+pub struct UrlTemplateSource {
+    pub template: String,
+    pub cache: Option<TileCache>,
+    pub metadata: TileSourceMetadata,
+    pub client: HttpClient,
+}
+
+impl UrlTemplateSource {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+            cache: None,
+            metadata: TileSourceMetadata::default(),
+            client: HttpClient::global().clone(),
+        }
+    }
+
+    /// Takes the tile URL and metadata from a TileJSON, `None` if it lists no tile URLs
+    pub fn from_tilejson(tilejson: &TileJson) -> Option<Self> {
+        let mut source = Self::new(tilejson.tile_template()?);
+        source.metadata = tilejson.metadata();
+        Some(source)
+    }
+
+    /// Keeps downloaded tiles in `cache` and reads them back from there until they expire
+    pub fn with_cache(mut self, cache: TileCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Opens this template's cache as configured, or goes without one
+    pub fn with_cache_settings(mut self, settings: &CacheSettings) -> Self {
+        self.cache = settings.open(&self.template);
+        self
+    }
+
+    fn store(&self, x: u64, y: u64, zoom: u64, data: &[u8], meta: &CacheMeta) {
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.put(x, y, zoom, data, meta) {
+                warn!("Failed to cache tile {}/{}/{}: {}", zoom, x, y, e);
+            }
+        }
+    }
+}
+
+impl TileSource for UrlTemplateSource {
+    fn fetch(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, TileError> {
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(x, y, zoom));
+        if let Some(cached) = &cached {
+            if cached.is_fresh() {
+                return Ok(cached.data.clone());
+            }
+        }
+
+        // Not cached or expired, ask the server, which can say the cached copy is still good
+        let url = fill_template(&self.template, x, y, zoom);
+        let headers: Vec<(&str, &str)> = cached
+            .as_ref()
+            .and_then(|cached| cached.meta.etag.as_deref())
+            .map(|etag| ("If-None-Match", etag))
+            .into_iter()
+            .collect();
+        match self.client.get(&url, &headers) {
+            Ok(response) => {
+                let meta = CacheMeta::from_response(&response);
+                if let (304, Some(cached)) = (response.status, &cached) {
+                    if let Some(meta) = meta {
+                        self.store(x, y, zoom, &cached.data, &meta);
+                    }
+                    return Ok(cached.data.clone());
+                }
+
+                // There is nothing at this z/x/y, drawn as an empty tile like local sources do
+                let body = if response.status == 204 { Vec::new() } else { response.body };
+                if let Some(meta) = meta {
+                    self.store(x, y, zoom, &body, &meta);
+                }
+                Ok(body)
+            }
+            // Servers answer 404 outside the area they cover, that's an empty tile and not worth asking again
+            Err(TileError::HttpStatus(404)) => {
+                self.store(x, y, zoom, &[], &CacheMeta::fresh());
+                Ok(Vec::new())
+            }
+            // An expired tile is better than none when the server can't be reached or keeps failing
+            Err(e @ (TileError::Network(_) | TileError::HttpStatus(500..))) => match cached {
+                Some(cached) => Ok(cached.data),
+                None => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn metadata(&self) -> TileSourceMetadata {
+        self.metadata.clone()
+    }
+}
+
+/// Reads a TileJSON and serves the tiles it points at
+pub fn tilejson_source(location: &str, cache_settings: &CacheSettings) -> Option<UrlTemplateSource> {
+    match TileJson::load(location, cache_settings.root.as_deref()) {
+        Ok(tilejson) => match UrlTemplateSource::from_tilejson(&tilejson) {
+            Some(source) => Some(source.with_cache_settings(cache_settings)),
+            None => {
+                error!("TileJSON {} lists no tile URLs", location);
+                None
+            }
+        },
+        Err(e) => {
+            error!("Failed to load TileJSON {}: {}", location, e);
+            None
+        }
+    }
+}
+
+/// OpenFreeMap's latest planet tiles, or the version we were built against if its TileJSON can't be had
+pub fn openfreemap_source(cache_settings: &CacheSettings) -> UrlTemplateSource {
+    match tilejson_source(OFM_TILEJSON_URL, cache_settings) {
+        Some(mut source) => {
+            // The planet's center and bounds are just the middle of the map, keep starting where we always have
+            source.metadata.center = None;
+            source.metadata.center_zoom = None;
+            source.metadata.bounds = None;
+            source
+        }
+        None => UrlTemplateSource::new(OFM_URL_TEMPLATE).with_cache_settings(cache_settings),
+    }
+}
+
+/// Reads `.pbf` tiles from a local directory, laid out either as `{z}/{x}/{y}.pbf` or `{z}_{x}_{y}.pbf`
+pub struct DirectorySource {
+    pub root: PathBuf,
+}
+
+impl DirectorySource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl TileSource for DirectorySource {
+    fn fetch(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, TileError> {
+        let nested = self.root.join(fill_template("{z}/{x}/{y}.pbf", x, y, zoom));
+        let flat = self.root.join(fill_template("{z}_{x}_{y}.pbf", x, y, zoom));
+        match [nested, flat].iter().find(|path| path.exists()) {
+            Some(path) => Ok(fs::read(path)?),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Keeps tiles in memory, mostly useful for tests and for tiles generated at runtime
+#[derive(Default)]
+pub struct MemorySource {
+    tiles: RwLock<HashMap<(u64, u64, u64), Vec<u8>>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, x: u64, y: u64, zoom: u64, data: Vec<u8>) {
+        self.tiles.write().unwrap().insert((zoom, x, y), data);
+    }
+}
+
+impl TileSource for MemorySource {
+    fn fetch(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, TileError> {
+        Ok(self.tiles.read().unwrap().get(&(zoom, x, y)).cloned().unwrap_or_default())
+    }
+}
+
+/// Picks the tile source from the command line:
+/// `--tiles-url <template>` for an XYZ server, `--tiles-dir <path>` for a local directory,
+/// `--tilejson <file or URL>` for the tiles a TileJSON points at,
+/// `--pmtiles <file>` for a PMTiles archive, `--mbtiles <file>` for an MBTiles database.
+/// Falls back to OpenFreeMap when none is given. Downloaded tiles are cached as set by `cache_settings_from_args`.
+pub fn source_from_args(args: impl IntoIterator<Item = String>) -> ActiveTileSource {
+    let args: Vec<String> = args.into_iter().collect();
+    let cache_settings = cache_settings_from_args(args.iter().cloned());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tiles-url" => {
+                if let Some(template) = args.next() {
+                    return ActiveTileSource::new(UrlTemplateSource::new(template).with_cache_settings(&cache_settings));
+                }
+            }
+            "--tilejson" => {
+                if let Some(location) = args.next() {
+                    if let Some(source) = tilejson_source(&location, &cache_settings) {
+                        return ActiveTileSource::new(source);
+                    }
+                }
+            }
+            "--tiles-dir" => {
+                if let Some(dir) = args.next() {
+                    if !Path::new(&dir).is_dir() {
+                        warn!("Tile directory {} does not exist", dir);
+                    }
+                    return ActiveTileSource::new(DirectorySource::new(dir));
+                }
+            }
+            "--pmtiles" => {
+                if let Some(path) = args.next() {
+                    match PmTilesSource::open(&path) {
+                        Ok(source) => return ActiveTileSource::new(source),
+                        Err(e) => error!("Failed to open PMTiles archive {}: {}", path, e),
+                    }
+                }
+            }
+            "--mbtiles" => {
+                if let Some(path) = args.next() {
+                    match MbTilesSource::open(&path) {
+                        Ok(source) => return ActiveTileSource::new(source),
+                        Err(e) => error!("Failed to open MBTiles database {}: {}", path, e),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    ActiveTileSource::new(openfreemap_source(&cache_settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{client, serve};
+
+    /// A source for the stub server at `url` with an empty cache of its own
+    fn cached_source(url: String, name: &str) -> UrlTemplateSource {
+        let root = std::env::temp_dir().join(format!("bevy-ofm-viewer-source-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let mut source = UrlTemplateSource::new(url).with_cache(TileCache::open(&root, "source", u64::MAX).unwrap());
+        source.client = client(1);
+        source
+    }
+
+    /// Caches `data` as already expired, with an ETag to revalidate it by
+    fn expired(source: &UrlTemplateSource, data: &[u8]) {
+        let meta = CacheMeta {
+            etag: Some("\"v1\"".to_string()),
+            expires: 0,
+        };
+        source.cache.as_ref().unwrap().put(0, 0, 0, data, &meta).unwrap();
+    }
+
+    #[test]
+    fn revalidates_expired_tiles() {
+        let (url, requests) = serve(vec!["HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nCache-Control: max-age=60\r\nConnection: close\r\n\r\n"]);
+        let source = cached_source(url, "304");
+        expired(&source, b"tile");
+
+        assert_eq!(source.fetch(0, 0, 0).unwrap(), b"tile");
+        let request = requests.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(request.contains("if-none-match: \"v1\"\r\n"), "{}", request);
+        // The server said it's still good, so it's fresh again and served without asking
+        assert!(source.cache.as_ref().unwrap().get(0, 0, 0).unwrap().is_fresh());
+        assert_eq!(source.fetch(0, 0, 0).unwrap(), b"tile");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn replaces_changed_tiles() {
+        let (url, _) = serve(vec!["HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 3\r\nConnection: close\r\n\r\nnew"]);
+        let source = cached_source(url, "changed");
+        expired(&source, b"old");
+
+        assert_eq!(source.fetch(0, 0, 0).unwrap(), b"new");
+        let cached = source.cache.as_ref().unwrap().get(0, 0, 0).unwrap();
+        assert_eq!((cached.data.as_slice(), cached.meta.etag.as_deref()), (b"new".as_slice(), Some("\"v2\"")));
+    }
+
+    #[test]
+    fn expired_tiles_stand_in_when_the_server_fails() {
+        let unavailable = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let (url, requests) = serve(vec![unavailable; 2]);
+        let source = cached_source(url, "5xx");
+        expired(&source, b"old");
+        assert_eq!(source.fetch(0, 0, 0).unwrap(), b"old");
+        assert_eq!(requests.lock().unwrap().len(), 2, "the request should be retried first");
+
+        let (url, _) = serve(vec![unavailable; 2]);
+        let source = cached_source(url, "5xx-uncached");
+        assert!(matches!(source.fetch(0, 0, 0), Err(TileError::HttpStatus(503))));
+
+        // Nothing listening at all
+        let source = cached_source(serve(Vec::<String>::new()).0, "offline");
+        expired(&source, b"old");
+        assert_eq!(source.fetch(0, 0, 0).unwrap(), b"old");
+    }
+
+    #[test]
+    fn missing_tiles_are_empty_and_cached() {
+        for (namespace, response) in [
+            ("404", "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+            ("204", "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"),
+        ] {
+            let (url, requests) = serve(vec![response]);
+            let mut source = cached_source(url, namespace);
+            source.client = client(4);
+            assert_eq!(source.fetch(0, 0, 0).unwrap(), Vec::<u8>::new());
+            assert_eq!(requests.lock().unwrap().len(), 1, "HTTP {} was retried", response);
+
+            let cached = source.cache.as_ref().unwrap().get(0, 0, 0).unwrap();
+            assert!(cached.data.is_empty() && cached.is_fresh());
+            // Served from the cache, the stub server is gone by now
+            assert_eq!(source.fetch(0, 0, 0).unwrap(), Vec::<u8>::new());
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::{error::Error, fs, path::{Path, PathBuf}, time::Duration};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::{http::{HttpClient, HttpSettings}, projection::LonLat, tile_cache::{cache_namespace, write_atomically}, tile_source::TileSourceMetadata};
+
+/// Lists the current OpenFreeMap planet tiles, the tile URL changes with every new version
+pub const OFM_TILEJSON_URL: &str = "https://tiles.openfreemap.org/planet";
+
+// Spec: https://github.com/mapbox/tilejson-spec/tree/master/3.0.0
+/// A TileJSON document, only the parts the viewer uses
+#[derive(Debug, Clone, Deserialize)]
+pub struct TileJson {
+    pub tiles: Vec<String>,
+    pub minzoom: Option<u32>,
+    pub maxzoom: Option<u32>,
+    /// West, south, east, north in degrees
+    pub bounds: Option<[f64; 4]>,
+    /// Longitude, latitude and optionally a zoom
+    pub center: Option<Vec<f64>>,
+    pub attribution: Option<String>,
+}
+
+impl TileJson {
+    /// Loads a TileJSON from a file or an http(s) URL.
+    /// Documents fetched over the network are saved under `cache_root`, and the saved one is used when the network is down.
+    pub fn load(location: &str, cache_root: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        if !(location.starts_with("http://") || location.starts_with("https://")) {
+            return Ok(serde_json::from_str(&fs::read_to_string(location)?)?);
+        }
+
+        let saved = cache_root.map(|root| saved_path(root, location));
+        info!("Fetching TileJSON {}, the window opens once it is in", location);
+        // Startup waits on this, so it gives up after one short try and falls back to the saved one
+        let client = HttpClient::new(HttpSettings {
+            connect_timeout: Duration::from_secs(3),
+            timeout: Duration::from_secs(5),
+            max_retries: 0,
+            ..default()
+        });
+        let fetched = client
+            .get(location, &[])
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|response| Ok(String::from_utf8(response.body)?))
+            .and_then(|text| Ok((serde_json::from_str::<TileJson>(&text)?, text)));
+        match fetched {
+            Ok((tilejson, text)) => {
+                if let Some(saved) = &saved {
+                    let written = fs::create_dir_all(saved.parent().expect("saved TileJSONs are in a directory"))
+                        .and_then(|_| write_atomically(saved, text.as_bytes()));
+                    if let Err(e) = written {
+                        warn!("Failed to save TileJSON {}: {}", location, e);
+                    }
+                }
+                Ok(tilejson)
+            }
+            Err(e) => {
+                let Some(saved) = saved.filter(|saved| saved.exists()) else {
+                    return Err(e);
+                };
+                warn!("Failed to fetch TileJSON {} ({}), using the last one fetched", location, e);
+                Ok(serde_json::from_str(&fs::read_to_string(saved)?)?)
+            }
+        }
+    }
+
+    /// The first tile URL template, TileJSON allows several for spreading load over servers
+    pub fn tile_template(&self) -> Option<&str> {
+        self.tiles.first().map(String::as_str)
+    }
+
+    pub fn metadata(&self) -> TileSourceMetadata {
+        let defaults = TileSourceMetadata::default();
+        let center = self.center.as_deref().unwrap_or_default();
+        TileSourceMetadata {
+            min_zoom: self.minzoom.unwrap_or(defaults.min_zoom),
+            max_zoom: self.maxzoom.unwrap_or(defaults.max_zoom),
+            bounds: self.bounds,
+            center: match center {
+                [lon, lat, ..] => Some(LonLat::new(*lon, *lat)),
+                _ => None,
+            },
+            center_zoom: center.get(2).map(|zoom| *zoom as u32),
+            attribution: self.attribution.clone(),
+        }
+    }
+}
+
+fn saved_path(cache_root: &Path, location: &str) -> PathBuf {
+    cache_root.join("tilejson").join(format!("{}.json", cache_namespace(location)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::serve;
+
+    const TILEJSON: &str = r#"{"tilejson": "3.0.0", "tiles": ["https://tiles.example/{z}/{x}/{y}.pbf"], "maxzoom": 12}"#;
+
+    fn response(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn falls_back_to_the_saved_copy_offline() {
+        let root = std::env::temp_dir().join(format!("bevy-ofm-viewer-tilejson-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (url, requests) = serve(vec![response(TILEJSON)]);
+
+        let fetched = TileJson::load(&url, Some(&root)).unwrap();
+        assert_eq!(fetched.tile_template(), Some("https://tiles.example/{z}/{x}/{y}.pbf"));
+        assert_eq!(fs::read_to_string(saved_path(&root, &url)).unwrap(), TILEJSON);
+
+        // The stub server only answered once, and is gone now
+        let saved = TileJson::load(&url, Some(&root)).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!((saved.tiles, saved.maxzoom), (fetched.tiles, Some(12)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn fails_offline_without_a_saved_copy() {
+        let root = std::env::temp_dir().join(format!("bevy-ofm-viewer-tilejson-none-{}", std::process::id()));
+        let (url, _) = serve(Vec::<String>::new());
+        assert!(TileJson::load(&url, Some(&root)).is_err());
+        assert!(TileJson::load(&url, None).is_err());
+    }
+
+    #[test]
+    fn metadata_defaults() {
+        let defaults = TileSourceMetadata::default();
+        let tilejson = |json: &str| serde_json::from_str::<TileJson>(json).unwrap().metadata();
+
+        let bare = tilejson(r#"{"tiles": []}"#);
+        assert_eq!(bare, defaults);
+
+        let full = tilejson(r#"{"tiles": [], "minzoom": 0, "maxzoom": 15, "bounds": [-10, 40, 5, 60], "center": [0.1, 52.2, 9], "attribution": "OSM"}"#);
+        assert_eq!(
+            full,
+            TileSourceMetadata {
+                min_zoom: 0,
+                max_zoom: 15,
+                bounds: Some([-10.0, 40.0, 5.0, 60.0]),
+                center: Some(LonLat::new(0.1, 52.2)),
+                center_zoom: Some(9),
+                attribution: Some("OSM".to_string()),
+            }
+        );
+
+        // The zoom is optional, a center of fewer than two numbers is ignored
+        let no_zoom = tilejson(r#"{"tiles": [], "center": [0.1, 52.2]}"#);
+        assert_eq!((no_zoom.center, no_zoom.center_zoom), (Some(LonLat::new(0.1, 52.2)), None));
+        let short = tilejson(r#"{"tiles": [], "center": [0.1]}"#);
+        assert_eq!((short.center, short.center_zoom), (None, None));
+    }
+}
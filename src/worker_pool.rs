@@ -0,0 +1,342 @@
+use std::{panic::{self, AssertUnwindSafe}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Condvar, Mutex}, thread};
+
+use bevy::prelude::*;
+use crossbeam_channel::Sender;
+
+use crate::{error::TileError, labels::Labels, ofm_api::{get_ofm_data, TileRequest}, style::ActiveStyle, tile_source::ActiveTileSource};
+
+/// A finished tile job, on its way back to the map
+#[derive(Debug)]
+pub struct ChunkResult {
+    pub job: TileJob,
+    /// The labels the tile placed are marked with this, see `LabelIndex::forget_drawing`
+    pub drawing: u64,
+    pub data: Result<Vec<u8>, TileError>,
+}
+
+/// One tile to fetch and rasterize for a chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileJob {
+    pub chunk_pos: IVec2,
+    /// The `ChunkManager` generation the job was made for, results from older generations are thrown away
+    pub generation: u64,
+    pub x: u64,
+    pub y: u64,
+    pub zoom: u32,
+    pub tile_size: u32,
+    /// Drawing a tile that is already shown again, for a neighbour's label that now reaches into it
+    pub redraw: bool,
+}
+
+struct JobQueue {
+    jobs: Vec<TileJob>,
+    /// Chunk the camera is over, the job closest to it is handed out first
+    center: IVec2,
+}
+
+struct Shared {
+    queue: Mutex<JobQueue>,
+    available: Condvar,
+    in_progress: AtomicUsize,
+    /// Jobs a worker is on, with the flag that tells it to stop
+    running: Mutex<Vec<(TileJob, Arc<AtomicBool>)>>,
+    generation: AtomicU64,
+}
+
+/// A fixed number of threads working through tile jobs, nearest to the camera first
+#[derive(Resource, Clone)]
+pub struct TileWorkerPool {
+    shared: Arc<Shared>,
+    workers: usize,
+}
+
+/// How many tile workers to start, set with `--workers <count>`
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct WorkerPoolSettings {
+    pub workers: usize,
+}
+
+impl Default for WorkerPoolSettings {
+    fn default() -> Self {
+        // Leave a core for the main thread
+        let cores = thread::available_parallelism().map(|cores| cores.get()).unwrap_or(4);
+        Self {
+            workers: cores.saturating_sub(1).max(1),
+        }
+    }
+}
+
+pub fn worker_settings_from_args(args: impl IntoIterator<Item = String>) -> WorkerPoolSettings {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--workers" {
+            match args.next().and_then(|count| count.parse::<usize>().ok()) {
+                Some(workers) if workers > 0 => return WorkerPoolSettings { workers },
+                _ => warn!("--workers expects a positive number"),
+            }
+        }
+    }
+    WorkerPoolSettings::default()
+}
+
+impl TileWorkerPool {
+    pub fn new(settings: WorkerPoolSettings, source: ActiveTileSource, style: ActiveStyle, labels: Labels, sender: Sender<ChunkResult>) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(JobQueue {
+                jobs: Vec::new(),
+                center: IVec2::ZERO,
+            }),
+            available: Condvar::new(),
+            in_progress: AtomicUsize::new(0),
+            running: Mutex::new(Vec::new()),
+            generation: AtomicU64::new(0),
+        });
+
+        for i in 0..settings.workers {
+            let shared = shared.clone();
+            let source = source.clone();
+            let style = style.clone();
+            let labels = labels.clone();
+            let sender = sender.clone();
+            thread::Builder::new()
+                .name(format!("tile-worker-{}", i))
+                .spawn(move || {
+                    loop {
+                        let (job, cancel) = shared.next_job();
+                        // Went out of view, or the zoom changed, while this one was being worked on
+                        let cancelled = || cancel.load(Ordering::SeqCst) || job.generation != shared.generation.load(Ordering::SeqCst);
+                        let drawing = labels.new_drawing();
+                        // A bad tile shouldn't take the worker down without telling the map
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            let request = TileRequest {
+                                source: source.as_ref(),
+                                style: &style,
+                                labels: &labels,
+                                x: job.x,
+                                y: job.y,
+                                zoom: job.zoom as u64,
+                                tile_size: job.tile_size,
+                                drawing,
+                            };
+                            get_ofm_data(&request, &cancelled)
+                        })).unwrap_or_else(|_| Err(TileError::Render("the tile worker panicked".to_string())));
+                        shared.finish(&cancel);
+
+                        let data = match result {
+                            // Still sent when it got drawn, so the map can forget the labels it placed
+                            Ok(_) if cancelled() => Err(TileError::Cancelled),
+                            Err(_) if cancelled() => continue,
+                            result => result,
+                        };
+                        let result = ChunkResult { job, drawing, data };
+                        if sender.send(result).is_err() {
+                            // The map is gone, so is the app
+                            break;
+                        }
+                    }
+                })
+                .expect("Failed to start tile worker");
+        }
+
+        Self {
+            shared,
+            workers: settings.workers,
+        }
+    }
+
+    pub fn submit(&self, job: TileJob) {
+        self.shared.queue.lock().unwrap().jobs.push(job);
+        self.shared.available.notify_one();
+    }
+
+    /// Makes the queue favour the chunks around `center`
+    pub fn set_center(&self, center: IVec2) {
+        self.shared.queue.lock().unwrap().center = center;
+    }
+
+    /// Drops the queued jobs `keep` returns false for and stops the running ones, and returns them all.
+    /// A stopped job sends no result, or `TileError::Cancelled` if it was already drawn.
+    pub fn cancel(&self, keep: impl Fn(&TileJob) -> bool) -> Vec<TileJob> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let (kept, mut cancelled): (Vec<TileJob>, Vec<TileJob>) = queue.jobs.drain(..).partition(&keep);
+        queue.jobs = kept;
+        for (job, cancel) in self.shared.running.lock().unwrap().iter() {
+            if !keep(job) && !cancel.swap(true, Ordering::SeqCst) {
+                cancelled.push(*job);
+            }
+        }
+        cancelled
+    }
+
+    /// Drops every queued job, and the results of the ones already running once they finish
+    pub fn start_generation(&self, generation: u64) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.jobs.clear();
+        self.shared.generation.store(generation, Ordering::SeqCst);
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().jobs.len()
+    }
+
+    pub fn in_progress(&self) -> usize {
+        self.shared.in_progress.load(Ordering::SeqCst)
+    }
+}
+
+impl Shared {
+    /// Blocks until there is a job, and hands it out with the flag `cancel` sets on it
+    fn next_job(&self) -> (TileJob, Arc<AtomicBool>) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let center = queue.center;
+            let nearest = queue
+                .jobs
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, job)| job.chunk_pos.distance_squared(center))
+                .map(|(i, _)| i);
+            if let Some(i) = nearest {
+                self.in_progress.fetch_add(1, Ordering::SeqCst);
+                let job = queue.jobs.swap_remove(i);
+                let cancel = Arc::new(AtomicBool::new(false));
+                self.running.lock().unwrap().push((job, cancel.clone()));
+                return (job, cancel);
+            }
+            queue = self.available.wait(queue).unwrap();
+        }
+    }
+
+    fn finish(&self, cancel: &Arc<AtomicBool>) {
+        self.running.lock().unwrap().retain(|(_, running)| !Arc::ptr_eq(running, cancel));
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crossbeam_channel::{unbounded, Receiver};
+
+    use super::*;
+    use crate::tile_source::TileSource;
+
+    /// Tells the test which tile it was asked for, then holds the worker until the test lets it go
+    struct GatedSource {
+        fetched: Sender<u64>,
+        release: Receiver<()>,
+    }
+
+    impl TileSource for GatedSource {
+        fn fetch(&self, x: u64, _y: u64, _zoom: u64) -> Result<Vec<u8>, TileError> {
+            self.fetched.send(x).unwrap();
+            let _ = self.release.recv();
+            Ok(Vec::new())
+        }
+    }
+
+    struct TestPool {
+        pool: TileWorkerPool,
+        fetched: Receiver<u64>,
+        release: Sender<()>,
+        results: Receiver<ChunkResult>,
+    }
+
+    impl TestPool {
+        /// One worker, already busy on the tile at x 100 so the jobs submitted next wait in the queue
+        fn busy() -> Self {
+            let (fetched_sender, fetched) = unbounded();
+            let (release, release_receiver) = unbounded();
+            let (sender, results) = unbounded();
+            let source = ActiveTileSource::new(GatedSource {
+                fetched: fetched_sender,
+                release: release_receiver,
+            });
+            let pool = TileWorkerPool::new(WorkerPoolSettings { workers: 1 }, source, ActiveStyle::default(), Labels::default(), sender);
+            pool.submit(job(100, 0));
+            assert_eq!(fetched.recv_timeout(Duration::from_secs(5)), Ok(100));
+            Self { pool, fetched, release, results }
+        }
+
+        /// Lets the worker through `count` more fetches
+        fn release(&self, count: usize) {
+            for _ in 0..count {
+                self.release.send(()).unwrap();
+            }
+        }
+
+        fn next_result(&self) -> ChunkResult {
+            self.results.recv_timeout(Duration::from_secs(5)).expect("a tile result")
+        }
+    }
+
+    /// A job for the chunk at `x` on the bottom row, drawing the tile of the same x
+    fn job(x: i32, generation: u64) -> TileJob {
+        TileJob {
+            chunk_pos: IVec2::new(x, 0),
+            generation,
+            x: x as u64,
+            y: 0,
+            zoom: 14,
+            tile_size: 16,
+            redraw: false,
+        }
+    }
+
+    #[test]
+    fn nearest_jobs_first() {
+        let test = TestPool::busy();
+        test.pool.set_center(IVec2::new(4, 0));
+        for x in [1, 6, 9, 3, 4] {
+            test.pool.submit(job(x, 0));
+        }
+        assert_eq!(test.pool.queued(), 5);
+        test.release(6);
+
+        let order: Vec<i32> = (0..6).map(|_| test.next_result().job.chunk_pos.x).collect();
+        assert_eq!(order, [100, 4, 3, 6, 1, 9]);
+        let fetched: Vec<u64> = test.fetched.try_iter().collect();
+        assert_eq!(fetched, [4, 3, 6, 1, 9]);
+    }
+
+    #[test]
+    fn cancelled_jobs_are_dropped() {
+        let test = TestPool::busy();
+        for x in [1, 2, 3] {
+            test.pool.submit(job(x, 0));
+        }
+        let cancelled: Vec<i32> = test.pool.cancel(|job| job.x != 100 && job.x != 2).iter().map(|job| job.chunk_pos.x).collect();
+        assert_eq!(cancelled, [2, 100]);
+        assert_eq!(test.pool.queued(), 2);
+        test.release(3);
+
+        // The running one was stopped before it got drawn, so it sends nothing
+        let order: Vec<i32> = (0..2).map(|_| test.next_result().job.chunk_pos.x).collect();
+        assert_eq!(order, [1, 3]);
+        assert!(test.results.recv_timeout(Duration::from_millis(200)).is_err());
+        let fetched: Vec<u64> = test.fetched.try_iter().collect();
+        assert_eq!(fetched, [1, 3]);
+    }
+
+    #[test]
+    fn older_generations_are_thrown_away() {
+        let test = TestPool::busy();
+        test.pool.submit(job(1, 0));
+        test.pool.submit(job(2, 0));
+        test.pool.start_generation(1);
+        assert_eq!(test.pool.queued(), 0);
+        test.pool.submit(job(3, 1));
+        test.release(2);
+
+        let result = test.next_result();
+        assert_eq!((result.job.chunk_pos.x, result.job.generation), (3, 1));
+        assert!(test.results.recv_timeout(Duration::from_millis(200)).is_err());
+        let fetched: Vec<u64> = test.fetched.try_iter().collect();
+        assert_eq!(fetched, [3]);
+    }
+}
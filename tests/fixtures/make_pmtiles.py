@@ -0,0 +1,92 @@
+"""Writes leaves.pmtiles, a tiny PMTiles v3 archive for the pmtiles tests.
+
+The root directory only points at two leaf directories, both directories are gzipped,
+and tile ids 5 to 8 share their bytes through a run length of 4.
+Spec: https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+"""
+
+import gzip
+import struct
+from pathlib import Path
+
+
+def zxy_to_tile_id(z, x, y):
+    # As in the reference implementation, kept separate from the Rust one on purpose
+    acc = sum(4**i for i in range(z))
+    d = 0
+    s = (1 << z) // 2
+    while s > 0:
+        rx = 1 if x & s else 0
+        ry = 1 if y & s else 0
+        d += s * s * ((3 * rx) ^ ry)
+        if ry == 0:
+            if rx == 1:
+                x = s - 1 - x
+                y = s - 1 - y
+            x, y = y, x
+        s //= 2
+    return acc + d
+
+
+def varint(value):
+    out = bytearray()
+    while value >= 0x80:
+        out.append(value & 0x7F | 0x80)
+        value >>= 7
+    out.append(value)
+    return bytes(out)
+
+
+def directory(entries):
+    """entries are (tile_id, run_length, length, offset), offset None means right after the previous entry"""
+    out = varint(len(entries))
+    last_id = 0
+    for tile_id, _, _, _ in entries:
+        out += varint(tile_id - last_id)
+        last_id = tile_id
+    for _, run_length, _, _ in entries:
+        out += varint(run_length)
+    for _, _, length, _ in entries:
+        out += varint(length)
+    for _, _, _, offset in entries:
+        out += varint(0 if offset is None else offset + 1)
+    return gzip.compress(out, mtime=0)
+
+
+def main():
+    tiles = [b"tile 0/0/0", b"tile 1/0/0", b"tile 1/0/1", b"tile 1/1/1", b"tile 1/1/0", b"the same for 5 to 8", b"tile 2/2/3"]
+    data = b"".join(tiles)
+    offsets = [sum(len(tile) for tile in tiles[:i]) for i in range(len(tiles))]
+
+    leaf_a = directory([(i, 1, len(tiles[i]), offsets[i] if i == 0 else None) for i in range(5)])
+    leaf_b = directory([(5, 4, len(tiles[5]), offsets[5]), (zxy_to_tile_id(2, 2, 3), 1, len(tiles[6]), None)])
+    leaves = leaf_a + leaf_b
+    # A run length of zero points at a leaf directory
+    root = directory([(0, 0, len(leaf_a), 0), (5, 0, len(leaf_b), len(leaf_a))])
+    metadata = gzip.compress(b'{"name":"leaves"}', mtime=0)
+
+    root_offset = 127
+    metadata_offset = root_offset + len(root)
+    leaves_offset = metadata_offset + len(metadata)
+    data_offset = leaves_offset + len(leaves)
+    header = b"PMTiles" + bytes([3])
+    header += struct.pack(
+        "<QQQQQQQQQQQ",
+        root_offset, len(root),
+        metadata_offset, len(metadata),
+        leaves_offset, len(leaves),
+        data_offset, len(data),
+        10, 7, 7,
+    )
+    # Clustered, gzipped directories, uncompressed vector tiles, zooms 0 to 2
+    header += bytes([1, 2, 1, 1, 0, 2])
+    e7 = lambda degrees: round(degrees * 10_000_000)
+    header += struct.pack("<iiii", e7(-180), e7(-85), e7(180), e7(85))
+    header += bytes([1]) + struct.pack("<ii", e7(0.1218), e7(52.2053))
+    assert len(header) == 127
+
+    Path(__file__).with_name("leaves.pmtiles").write_bytes(header + root + metadata + leaves + data)
+
+
+if __name__ == "__main__":
+    main()